@@ -0,0 +1,358 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+#[cfg(unix)]
+use std::io::{Read, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+#[cfg(unix)]
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+use crate::executor::shell_command;
+
+// How often the response file is looked for
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+// How long a call written to the approval directory waits for an answer without a timeout set
+const DEFAULT_DIR_TIMEOUT: Duration = Duration::from_secs(600);
+
+// How calls that need approval get it, set in the config file's `approval:` block. At most one of
+// `command`, `pipe` and `dir` is set, and without any of them the reviewer is asked on the
+// terminal, if there is one.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ApprovalConfig {
+  /// Command that gets the call as JSON on stdin and exits with 0 to allow it. What it prints is
+  /// the reason.
+  pub command: Option<String>,
+  /// Named pipes the call is written to as a line of JSON, and the answer read from
+  pub pipe: Option<PipeConfig>,
+  /// Directory each call is written to as `<name>.json`, the answer being read from
+  /// `<name>.response`
+  pub dir: Option<PathBuf>,
+  /// Seconds to wait for an answer before the call is denied, 600 by default with `dir`
+  pub timeout: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PipeConfig {
+  pub requests: PathBuf,
+  pub responses: PathBuf,
+}
+
+// Whether a tool's calls need approval
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Approval {
+  #[default]
+  Never,
+  Required,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApprovalRequest<'a> {
+  pub id: &'a str,
+  pub tool: &'a str,
+  pub arguments: &'a serde_json::Value,
+  /// The command the tool runs, for tools that run one
+  pub command: Option<&'a str>,
+  /// Why the call needs approval
+  pub reason: &'a str,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Review {
+  Approved,
+  Denied(String),
+}
+
+enum Method {
+  Command(String),
+  Pipe(PipeConfig),
+  Dir(PathBuf),
+  #[cfg(unix)]
+  Tty,
+  Unavailable,
+}
+
+pub struct Approver {
+  method: Method,
+  shell: String,
+  hidden_env: Vec<String>,
+  timeout: Option<Duration>,
+  requests: AtomicUsize,
+}
+
+impl Approver {
+  pub fn new(config: &ApprovalConfig, shell: &str) -> Self {
+    let method = if let Some(command) = &config.command {
+      Method::Command(command.clone())
+    } else if let Some(pipe) = &config.pipe {
+      Method::Pipe(pipe.clone())
+    } else if let Some(dir) = &config.dir {
+      Method::Dir(dir.clone())
+    } else {
+      terminal().unwrap_or(Method::Unavailable)
+    };
+    let timeout = match (config.timeout, &method) {
+      (Some(timeout), _) => Some(Duration::from_secs(timeout)),
+      // Nobody might ever look at the directory
+      (None, Method::Dir(_)) => Some(DEFAULT_DIR_TIMEOUT),
+      (None, _) => None,
+    };
+    Self {
+      method,
+      shell: shell.to_string(),
+      hidden_env: Vec::new(),
+      timeout,
+      requests: AtomicUsize::new(0),
+    }
+  }
+
+  // Keeps variables such as API tokens out of the approver command's environment
+  pub fn with_hidden_env(mut self, vars: Vec<String>) -> Self {
+    self.hidden_env = vars;
+    self
+  }
+
+  pub async fn review(&self, request: &ApprovalRequest<'_>) -> Result<Review> {
+    let review = self.ask(request);
+    match self.timeout {
+      Some(timeout) => tokio::time::timeout(timeout, review)
+        .await
+        .unwrap_or_else(|_| {
+          Ok(Review::Denied(format!(
+            "no answer within {} seconds",
+            timeout.as_secs()
+          )))
+        }),
+      None => review.await,
+    }
+  }
+
+  async fn ask(&self, request: &ApprovalRequest<'_>) -> Result<Review> {
+    let json = serde_json::to_string(request)?;
+    match &self.method {
+      Method::Command(command) => {
+        let mut child =
+          tokio::process::Command::from(shell_command(&self.shell, command, &self.hidden_env))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        // Approvers that decide without reading the call may exit before it's written
+        if let Some(mut stdin) = child.stdin.take()
+          && let Err(e) = stdin.write_all(json.as_bytes()).await
+          && e.kind() != std::io::ErrorKind::BrokenPipe
+        {
+          return Err(e.into());
+        }
+        let output = child.wait_with_output().await?;
+        let reason = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        Ok(if output.status.success() {
+          Review::Approved
+        } else if reason.is_empty() {
+          Review::Denied(format!("the approver exited with {}", output.status))
+        } else {
+          Review::Denied(reason)
+        })
+      }
+      Method::Pipe(pipe) => {
+        // Opening a named pipe waits for the other end
+        let mut requests = tokio::fs::OpenOptions::new()
+          .write(true)
+          .open(&pipe.requests)
+          .await?;
+        requests.write_all(format!("{}\n", json).as_bytes()).await?;
+        drop(requests);
+        let responses = tokio::fs::File::open(&pipe.responses).await?;
+        let mut line = String::new();
+        tokio::io::BufReader::new(responses)
+          .read_line(&mut line)
+          .await?;
+        Ok(parse_answer(&line))
+      }
+      Method::Dir(dir) => {
+        let name = format!(
+          "{}-{}",
+          std::process::id(),
+          self.requests.fetch_add(1, Ordering::Relaxed) + 1
+        );
+        tokio::fs::create_dir_all(dir).await?;
+        let call = dir.join(format!("{}.json", name));
+        let response = dir.join(format!("{}.response", name));
+        // Both files go once the call is decided, or given up on
+        let _pending = Pending(vec![call.clone(), response.clone()]);
+        tokio::fs::write(&call, &json).await?;
+        loop {
+          if let Ok(answer) = tokio::fs::read_to_string(&response).await
+            && !answer.trim().is_empty()
+          {
+            return Ok(parse_answer(&answer));
+          }
+          tokio::time::sleep(POLL_INTERVAL).await;
+        }
+      }
+      #[cfg(unix)]
+      Method::Tty => {
+        let mut prompt = format!("\n{} needs approval ({})\n", request.tool, request.reason);
+        if let Some(command) = request.command {
+          prompt.push_str(&format!("  command: {}\n", command));
+        }
+        prompt.push_str(&format!(
+          "  arguments: {}\nAllow? [y/N, or the reason to deny] ",
+          request.arguments
+        ));
+        let line = read_tty_line(&prompt).await?;
+        Ok(match line.trim() {
+          "y" | "Y" | "yes" => Review::Approved,
+          "" | "n" | "N" | "no" => Review::Denied("denied by the reviewer".to_string()),
+          reason => Review::Denied(reason.to_string()),
+        })
+      }
+      Method::Unavailable => Ok(Review::Denied(
+        "approval is needed, but there's no terminal to ask on and no approver is configured"
+          .to_string(),
+      )),
+    }
+  }
+}
+
+// Removes the files of a call to the approval directory when dropped
+struct Pending(Vec<PathBuf>);
+
+impl Drop for Pending {
+  fn drop(&mut self) {
+    for path in &self.0 {
+      let _ = std::fs::remove_file(path);
+    }
+  }
+}
+
+// Reviewers are only asked on the terminal on unix, where it can be read without blocking
+#[cfg(unix)]
+fn terminal() -> Option<Method> {
+  std::fs::File::open("/dev/tty").ok().map(|_| Method::Tty)
+}
+
+#[cfg(not(unix))]
+fn terminal() -> Option<Method> {
+  None
+}
+
+// Asks on the terminal without tying up a thread, so that a timeout or Ctrl-C stops the read
+// rather than leaving it waiting for Enter
+#[cfg(unix)]
+async fn read_tty_line(prompt: &str) -> Result<String> {
+  let mut tty = std::fs::OpenOptions::new()
+    .read(true)
+    .write(true)
+    .custom_flags(libc::O_NONBLOCK)
+    .open("/dev/tty")?;
+  tty.write_all(prompt.as_bytes())?;
+  let tty = AsyncFd::new(tty)?;
+  let mut line = Vec::new();
+  while !line.contains(&b'\n') {
+    let mut guard = tty.readable().await?;
+    let mut buffer = [0; 1024];
+    match guard.try_io(|tty| tty.get_ref().read(&mut buffer)) {
+      Ok(Ok(0)) => break,
+      Ok(Ok(n)) => line.extend_from_slice(&buffer[..n]),
+      Ok(Err(e)) => return Err(e.into()),
+      // Not readable after all, wait again
+      Err(_) => {}
+    }
+  }
+  Ok(String::from_utf8_lossy(&line).into_owned())
+}
+
+// Answers from pipes and files are `allow`, or `deny` followed by the reason
+fn parse_answer(answer: &str) -> Review {
+  let answer = answer.trim();
+  let (word, reason) = answer
+    .split_once(char::is_whitespace)
+    .unwrap_or((answer, ""));
+  match word.to_lowercase().as_str() {
+    "allow" | "approve" | "yes" => Review::Approved,
+    _ if reason.trim().is_empty() => Review::Denied("denied by the reviewer".to_string()),
+    _ => Review::Denied(reason.trim().to_string()),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_command_and_dir_approvers() {
+    let arguments = serde_json::json!({ "replicas": 20 });
+    let request = ApprovalRequest {
+      id: "call_1",
+      tool: "scale",
+      arguments: &arguments,
+      command: Some("kubectl scale"),
+      reason: "scale requires approval",
+    };
+
+    let config = ApprovalConfig {
+      command: Some(
+        "grep -q '\"replicas\":20' && { echo too many; exit 1; } || exit 0".to_string(),
+      ),
+      ..Default::default()
+    };
+    let review = Approver::new(&config, "bash")
+      .review(&request)
+      .await
+      .unwrap();
+    assert_eq!(review, Review::Denied("too many".to_string()));
+
+    let config = ApprovalConfig {
+      command: Some("cat > /dev/null; test -z \"$HOME\"".to_string()),
+      ..Default::default()
+    };
+    let review = Approver::new(&config, "bash")
+      .with_hidden_env(vec!["HOME".to_string()])
+      .review(&request)
+      .await
+      .unwrap();
+    assert_eq!(review, Review::Approved);
+
+    let dir = std::env::temp_dir().join(format!("llmcli-approvals-{}", std::process::id()));
+    let config = ApprovalConfig {
+      dir: Some(dir.clone()),
+      timeout: Some(5),
+      ..Default::default()
+    };
+    let approver = Approver::new(&config, "bash");
+    let response = dir.join(format!("{}-1.response", std::process::id()));
+    let supervisor = async {
+      while !dir.join(format!("{}-1.json", std::process::id())).exists() {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+      }
+      std::fs::write(&response, "allow\n").unwrap();
+    };
+    let (review, ()) = tokio::join!(approver.review(&request), supervisor);
+    assert_eq!(review.unwrap(), Review::Approved);
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+    assert_eq!(
+      Approver::new(&config, "bash").timeout,
+      Some(Duration::from_secs(5))
+    );
+    let config = ApprovalConfig {
+      dir: Some(dir.clone()),
+      ..Default::default()
+    };
+    assert_eq!(
+      Approver::new(&config, "bash").timeout,
+      Some(DEFAULT_DIR_TIMEOUT)
+    );
+    assert_eq!(
+      parse_answer("deny not during business hours"),
+      Review::Denied("not during business hours".to_string())
+    );
+
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+}
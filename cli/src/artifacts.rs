@@ -0,0 +1,342 @@
+use anyhow::Result;
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::llm_client::{FunctionDefinition, ToolDefinition};
+
+pub const READ_OUTPUT: &str = "read_output";
+pub const GREP_OUTPUT: &str = "grep_output";
+
+// Lines read_output returns unless asked for a length, and the characters it returns at most
+const DEFAULT_READ_LINES: usize = 200;
+const MAX_READ_LENGTH: usize = 20_000;
+
+// Matching lines grep_output returns at most, and how much of each around the match
+const MAX_MATCHES: usize = 100;
+const MAX_MATCH_LENGTH: usize = 500;
+
+// Longer lines, such as minified JSON, are saved split into lines of this many characters so
+// that all of them can be paged through
+const MAX_LINE_LENGTH: usize = 1000;
+
+// Tool outputs too long to hand to the model whole. They're saved to files for the session, and
+// the model pages through them with the read_output and grep_output builtins.
+pub struct Artifacts {
+  dir: PathBuf,
+  files: Mutex<HashMap<String, PathBuf>>,
+}
+
+#[derive(Deserialize)]
+struct ReadArgs {
+  handle: String,
+  #[serde(default)]
+  offset: usize,
+  length: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct GrepArgs {
+  handle: String,
+  pattern: String,
+}
+
+impl Artifacts {
+  // Each session gets a directory of its own under `base`
+  pub fn new(base: &Path) -> Self {
+    let session = format!(
+      "llmcli-{}-{}",
+      chrono::Local::now().format("%Y%m%d-%H%M%S"),
+      std::process::id()
+    );
+    Self {
+      dir: base.join(session),
+      files: Mutex::new(HashMap::new()),
+    }
+  }
+
+  // Saves the output and returns its first and last lines, about `max_length` characters of
+  // them, with the handle to read the rest by
+  pub fn spill(&self, output: &str, max_length: usize) -> Result<String> {
+    let output = &split_long_lines(output);
+    let mut files = self.files.lock().unwrap();
+    let handle = format!("out-{}", files.len() + 1);
+    if files.is_empty() {
+      // Only we may read the outputs, and a directory someone else made in our place is refused
+      if let Some(base) = self.dir.parent() {
+        std::fs::create_dir_all(base)?;
+      }
+      let mut dir = std::fs::DirBuilder::new();
+      #[cfg(unix)]
+      dir.mode(0o700);
+      dir.create(&self.dir)?;
+    }
+    let path = self.dir.join(format!("{}.txt", handle));
+    let mut file = std::fs::OpenOptions::new();
+    file.write(true).create_new(true);
+    #[cfg(unix)]
+    file.mode(0o600);
+    file.open(&path)?.write_all(output.as_bytes())?;
+    tracing::info!(
+      "Saved {} characters of output to {}",
+      output.chars().count(),
+      path.display()
+    );
+    files.insert(handle.clone(), path);
+    Ok(excerpt(&handle, output, max_length))
+  }
+
+  pub fn execute(&self, name: &str, input: &serde_json::Value) -> Result<String> {
+    match name {
+      READ_OUTPUT => self.read(serde_json::from_value(input.clone())?),
+      GREP_OUTPUT => self.grep(serde_json::from_value(input.clone())?),
+      _ => anyhow::bail!("Unknown builtin tool {}", name),
+    }
+  }
+
+  fn load(&self, handle: &str) -> Result<String> {
+    let files = self.files.lock().unwrap();
+    let path = files
+      .get(handle)
+      .ok_or_else(|| anyhow::anyhow!("There is no saved output with the handle {}", handle))?;
+    Ok(std::fs::read_to_string(path)?)
+  }
+
+  fn read(&self, args: ReadArgs) -> Result<String> {
+    let output = self.load(&args.handle)?;
+    let lines: Vec<&str> = output.lines().collect();
+    if args.offset >= lines.len() {
+      anyhow::bail!("The output has only {} lines", lines.len());
+    }
+    let length = args.length.unwrap_or(DEFAULT_READ_LINES).max(1);
+    let end = (args.offset + length).min(lines.len());
+
+    let mut text = lines[args.offset..end].join("\n");
+    if text.chars().count() > MAX_READ_LENGTH {
+      text = prefix(&text, MAX_READ_LENGTH).to_string();
+      text.push_str("\n[Truncated, read fewer lines at a time]");
+    } else if end < lines.len() {
+      text.push_str(&format!(
+        "\n[Lines {} to {} of {}]",
+        args.offset + 1,
+        end,
+        lines.len()
+      ));
+    }
+    Ok(text)
+  }
+
+  fn grep(&self, args: GrepArgs) -> Result<String> {
+    let output = self.load(&args.handle)?;
+    let regex = Regex::new(&args.pattern)?;
+    let matches: Vec<String> = output
+      .lines()
+      .enumerate()
+      .filter(|(_, line)| regex.is_match(line))
+      .take(MAX_MATCHES + 1)
+      .map(|(i, line)| {
+        // Long lines are shown from a little before the match
+        let start = regex.find(line).map_or(0, |m| m.start());
+        let before = line[..start]
+          .chars()
+          .count()
+          .saturating_sub(MAX_MATCH_LENGTH / 5);
+        let from = line.char_indices().nth(before).map_or(0, |(j, _)| j);
+        format!("{}: {}", i + 1, prefix(&line[from..], MAX_MATCH_LENGTH))
+      })
+      .collect();
+    Ok(match matches.len() {
+      0 => "No lines match".to_string(),
+      n if n > MAX_MATCHES => format!(
+        "{}\n[More than {} lines match, use a narrower pattern]",
+        matches[..MAX_MATCHES].join("\n"),
+        MAX_MATCHES
+      ),
+      _ => matches.join("\n"),
+    })
+  }
+}
+
+// The builtins offered to the model when any tool's output can be saved
+pub fn tool_definitions() -> Vec<ToolDefinition> {
+  let definition = |name: &str, description: &str, parameters: serde_json::Value| ToolDefinition {
+    function: FunctionDefinition {
+      name: name.to_string(),
+      description: description.to_string(),
+      parameters,
+    },
+    tool_type: "function".to_string(),
+  };
+  vec![
+    definition(
+      READ_OUTPUT,
+      "Read lines of a tool output that was too long to show in full. Lines longer than 1000 \
+       characters were split.",
+      json!({
+        "type": "object",
+        "properties": {
+          "handle": { "type": "string", "description": "Handle of the saved output" },
+          "offset": { "type": "integer", "description": "Lines to skip (default 0)" },
+          "length": { "type": "integer", "description": "Lines to read (default 200)" },
+        },
+        "required": ["handle"],
+      }),
+    ),
+    definition(
+      GREP_OUTPUT,
+      "Find the lines of a tool output that was too long to show in full that match a regex, \
+       with their line numbers",
+      json!({
+        "type": "object",
+        "properties": {
+          "handle": { "type": "string", "description": "Handle of the saved output" },
+          "pattern": { "type": "string", "description": "Regular expression to search for" },
+        },
+        "required": ["handle", "pattern"],
+      }),
+    ),
+  ]
+}
+
+fn excerpt(handle: &str, output: &str, max_length: usize) -> String {
+  let lines: Vec<&str> = output.lines().collect();
+  let half = max_length / 2;
+  let mut head = 0;
+  let mut length = 0;
+  let chars = |line: &str| line.chars().count();
+  while head < lines.len() && length + chars(lines[head]) < half {
+    length += chars(lines[head]) + 1;
+    head += 1;
+  }
+  let mut tail = lines.len();
+  length = 0;
+  while tail > head && length + chars(lines[tail - 1]) < half {
+    length += chars(lines[tail - 1]) + 1;
+    tail -= 1;
+  }
+
+  let mut excerpt = format!(
+    "[The output is {} characters in {} lines, too long to show in full. It was saved as {}, use \
+     {} or {} to see the rest.]\n",
+    output.chars().count(),
+    lines.len(),
+    handle,
+    READ_OUTPUT,
+    GREP_OUTPUT
+  );
+  match lines.first() {
+    // A single long line still gets its start shown
+    Some(first) if head == 0 => {
+      excerpt.push_str(prefix(first, half));
+      excerpt.push_str("...\n");
+    }
+    _ => {
+      excerpt.push_str(&lines[..head].join("\n"));
+      excerpt.push('\n');
+    }
+  }
+  if tail > head {
+    excerpt.push_str(&format!(
+      "[... lines {} to {} omitted ...]\n",
+      head + 1,
+      tail
+    ));
+  }
+  excerpt.push_str(&lines[tail..].join("\n"));
+  excerpt
+}
+
+fn split_long_lines(output: &str) -> String {
+  let mut split = String::with_capacity(output.len());
+  for line in output.split_inclusive('\n') {
+    let mut rest = line;
+    while rest.trim_end_matches('\n').chars().count() > MAX_LINE_LENGTH {
+      let (chunk, remainder) = rest.split_at(prefix(rest, MAX_LINE_LENGTH).len());
+      split.push_str(chunk);
+      split.push('\n');
+      rest = remainder;
+    }
+    split.push_str(rest);
+  }
+  split
+}
+
+// The first `length` characters of the text
+fn prefix(text: &str, length: usize) -> &str {
+  match text.char_indices().nth(length) {
+    Some((end, _)) => &text[..end],
+    None => text,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_spill_and_page_through_output() {
+    let dir = std::env::temp_dir().join(format!("llmcli-test-{}", std::process::id()));
+    let artifacts = Artifacts::new(&dir);
+    let output: String = (1..=1000).map(|i| format!("line {}\n", i)).collect();
+
+    let excerpt = artifacts.spill(&output, 200).unwrap();
+    #[cfg(unix)]
+    {
+      use std::os::unix::fs::PermissionsExt;
+      let mode = std::fs::metadata(&artifacts.dir)
+        .unwrap()
+        .permissions()
+        .mode();
+      assert_eq!(mode & 0o777, 0o700);
+    }
+    assert!(excerpt.contains("saved as out-1"));
+    assert!(excerpt.contains("\nline 1\n"));
+    assert!(excerpt.ends_with("line 1000"));
+    assert!(excerpt.len() < 400);
+
+    let read = artifacts
+      .execute(
+        READ_OUTPUT,
+        &json!({ "handle": "out-1", "offset": 499, "length": 2 }),
+      )
+      .unwrap();
+    assert_eq!(read, "line 500\nline 501\n[Lines 500 to 501 of 1000]");
+    let grep = artifacts
+      .execute(
+        GREP_OUTPUT,
+        &json!({ "handle": "out-1", "pattern": "^line 99\\d$" }),
+      )
+      .unwrap();
+    assert_eq!(grep.lines().count(), 10);
+    assert!(grep.starts_with("990: line 990"));
+    assert!(
+      artifacts
+        .execute(READ_OUTPUT, &json!({ "handle": "../x" }))
+        .is_err()
+    );
+
+    // A single line too long to read at once is split, and every part of it can be read
+    let minified = format!("[{}\"needle\"]", "\"é\",".repeat(10_000));
+    let excerpt = artifacts.spill(&minified, 100).unwrap();
+    assert!(excerpt.contains("saved as out-2"));
+    let last = artifacts
+      .execute(READ_OUTPUT, &json!({ "handle": "out-2", "offset": 40 }))
+      .unwrap();
+    assert!(last.ends_with("\"needle\"]"));
+    let grep = artifacts
+      .execute(
+        GREP_OUTPUT,
+        &json!({ "handle": "out-2", "pattern": "needle" }),
+      )
+      .unwrap();
+    assert!(grep.starts_with("41: ") && grep.contains("needle"));
+
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+}
@@ -0,0 +1,179 @@
+use anyhow::Result;
+use reqwest::header::{HeaderName, HeaderValue};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::process::Command;
+
+use crate::executor::shell_command;
+use crate::providers::ProviderKind;
+
+// Where a provider's API token comes from
+#[derive(Debug, Clone)]
+pub enum TokenSource {
+  Static(String),
+  File(PathBuf),
+  // A command run with the configured shell, without the variables in `hidden_env`
+  Command {
+    command: String,
+    shell: String,
+    hidden_env: Vec<String>,
+  },
+}
+
+// A provider's API token and how to present it. Tokens from files and commands are cached,
+// for `ttl` if given, and fetched again when the API rejects them.
+pub struct Credentials {
+  source: TokenSource,
+  ttl: Option<Duration>,
+  provider: ProviderKind,
+  header: Option<String>,
+  scheme: Option<String>,
+  cached: Mutex<Option<(String, Instant)>>,
+}
+
+impl Credentials {
+  pub fn new(source: TokenSource, provider: ProviderKind) -> Self {
+    Self {
+      source,
+      ttl: None,
+      provider,
+      header: None,
+      scheme: None,
+      cached: Mutex::new(None),
+    }
+  }
+
+  pub fn with_ttl(mut self, ttl: Option<Duration>) -> Self {
+    self.ttl = ttl;
+    self
+  }
+
+  // Overrides the provider's usual header, e.g. `api-key` for Azure
+  pub fn with_header(mut self, header: Option<String>, scheme: Option<String>) -> Self {
+    self.header = header;
+    self.scheme = scheme;
+    self
+  }
+
+  pub fn refreshable(&self) -> bool {
+    !matches!(self.source, TokenSource::Static(_))
+  }
+
+  pub fn invalidate(&self) {
+    *self.cached.lock().unwrap() = None;
+  }
+
+  pub async fn header(&self) -> Result<(HeaderName, HeaderValue)> {
+    let token = self.token().await?;
+    let (name, mut value) = match (&self.header, &self.scheme) {
+      (None, None) => self.provider.auth_header(&token)?,
+      (header, scheme) => {
+        let name = HeaderName::from_bytes(
+          header
+            .as_deref()
+            .unwrap_or("authorization")
+            .to_lowercase()
+            .as_bytes(),
+        )?;
+        let value = match scheme {
+          Some(scheme) => format!("{} {}", scheme, token),
+          None if name == reqwest::header::AUTHORIZATION => format!("Bearer {}", token),
+          None => token,
+        };
+        (name, HeaderValue::from_str(&value)?)
+      }
+    };
+    value.set_sensitive(true);
+    Ok((name, value))
+  }
+
+  async fn token(&self) -> Result<String> {
+    if let Some((token, fetched)) = &*self.cached.lock().unwrap()
+      && self.ttl.is_none_or(|ttl| fetched.elapsed() < ttl)
+    {
+      return Ok(token.clone());
+    }
+
+    let token = match &self.source {
+      TokenSource::Static(token) => token.clone(),
+      TokenSource::File(path) => tokio::fs::read_to_string(path)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to read token file {}: {}", path.display(), e))?,
+      TokenSource::Command {
+        command,
+        shell,
+        hidden_env,
+      } => {
+        let output = Command::from(shell_command(shell, command, hidden_env))
+          .stdin(std::process::Stdio::null())
+          .output()
+          .await?;
+        if !output.status.success() {
+          anyhow::bail!(
+            "Token command `{}` failed with exit code {:?}\nstderr: {}",
+            command,
+            output.status.code(),
+            String::from_utf8_lossy(&output.stderr)
+          );
+        }
+        String::from_utf8(output.stdout)?
+      }
+    };
+
+    let token = token.trim().to_string();
+    if token.is_empty() {
+      anyhow::bail!("The API token is empty");
+    }
+    *self.cached.lock().unwrap() = Some((token.clone(), Instant::now()));
+    Ok(token)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_credentials_header() {
+    let credentials = Credentials::new(
+      TokenSource::Command {
+        command: "echo secret".to_string(),
+        shell: "sh".to_string(),
+        hidden_env: Vec::new(),
+      },
+      ProviderKind::OpenAi,
+    );
+    let (name, value) = credentials.header().await.unwrap();
+    assert_eq!(name, "authorization");
+    assert_eq!(value, "Bearer secret");
+    assert!(value.is_sensitive());
+
+    let azure = Credentials::new(TokenSource::Static("key".to_string()), ProviderKind::OpenAi)
+      .with_header(Some("api-key".to_string()), None);
+    let (name, value) = azure.header().await.unwrap();
+    assert_eq!(name, "api-key");
+    assert_eq!(value, "key");
+
+    let failing = Credentials::new(
+      TokenSource::Command {
+        command: "exit 1".to_string(),
+        shell: "sh".to_string(),
+        hidden_env: Vec::new(),
+      },
+      ProviderKind::OpenAi,
+    );
+    assert!(failing.header().await.is_err());
+
+    // The command runs with the configured shell, not sh, and without the hidden variables
+    let bash = Credentials::new(
+      TokenSource::Command {
+        command: "echo ${BASH_VERSION:+bash}${HOME:+-home}".to_string(),
+        shell: "bash".to_string(),
+        hidden_env: vec!["HOME".to_string()],
+      },
+      ProviderKind::OpenAi,
+    );
+    assert_eq!(bash.header().await.unwrap().1, "Bearer bash");
+  }
+}
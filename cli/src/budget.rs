@@ -0,0 +1,193 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::exit::ExitStatus;
+use crate::usage::UsageTracker;
+
+// Hard limits on a run, set in the config file's `limits:` block and overridable from the CLI
+#[derive(Debug, Clone, Default, Serialize, Deserialize, clap::Args)]
+pub struct Limits {
+  /// Maximum number of turns before the model has to wrap up
+  #[arg(long)]
+  pub max_turns: Option<usize>,
+
+  /// Maximum number of calls of each tool (a tool's max_calls takes precedence)
+  #[arg(long)]
+  pub max_tool_calls: Option<usize>,
+
+  /// Maximum number of prompt and completion tokens over the run
+  #[arg(long)]
+  pub max_total_tokens: Option<u64>,
+
+  /// Maximum cost of the run in USD, needs pricing for the model
+  #[arg(long)]
+  pub max_cost: Option<f64>,
+
+  /// Maximum wall-clock time of the run in seconds
+  #[arg(long)]
+  pub timeout: Option<u64>,
+}
+
+impl Limits {
+  pub fn merge(self, overrides: Limits) -> Limits {
+    Limits {
+      max_turns: overrides.max_turns.or(self.max_turns),
+      max_tool_calls: overrides.max_tool_calls.or(self.max_tool_calls),
+      max_total_tokens: overrides.max_total_tokens.or(self.max_total_tokens),
+      max_cost: overrides.max_cost.or(self.max_cost),
+      timeout: overrides.timeout.or(self.timeout),
+    }
+  }
+}
+
+// Tracks a run against its limits. Once a limit is hit the model gets one more turn, without
+// tools, to wrap up.
+pub struct Budget {
+  limits: Limits,
+  started: Instant,
+  tool_calls: HashMap<String, usize>,
+}
+
+impl Budget {
+  pub fn new(limits: Limits) -> Self {
+    Self {
+      limits,
+      started: Instant::now(),
+      tool_calls: HashMap::new(),
+    }
+  }
+
+  // Returns why the run has to wrap up, if it does
+  pub fn exhausted(&self, turns: usize, usage: &UsageTracker) -> Option<String> {
+    let limits = &self.limits;
+    let total_tokens = usage.total.prompt_tokens + usage.total.completion_tokens;
+
+    if let Some(max) = limits.max_turns
+      && turns >= max
+    {
+      Some(format!("reached the limit of {} turns", max))
+    } else if let Some(max) = limits.max_total_tokens
+      && total_tokens >= max
+    {
+      Some(format!("used {} of {} tokens", total_tokens, max))
+    } else if let Some(max) = limits.max_cost
+      && let Some(cost) = usage.total_cost()
+      && cost >= max
+    {
+      Some(format!("spent ${:.4} of ${:.4}", cost, max))
+    } else if self.remaining_time() == Some(Duration::ZERO) {
+      Some(format!(
+        "ran out of time after {} seconds",
+        self.started.elapsed().as_secs()
+      ))
+    } else {
+      None
+    }
+  }
+
+  // Returns why the next request can't be sent, if its prompt would take the run past the token
+  // limit
+  pub fn request_exceeds(&self, prompt_tokens: u64, usage: &UsageTracker) -> Option<String> {
+    let total_tokens = usage.total.prompt_tokens + usage.total.completion_tokens;
+    match self.limits.max_total_tokens {
+      Some(max) if total_tokens + prompt_tokens > max => Some(format!(
+        "the next request needs about {} tokens, {} of {} are left",
+        prompt_tokens,
+        max.saturating_sub(total_tokens),
+        max
+      )),
+      _ => None,
+    }
+  }
+
+  // Counts a call of the tool, or returns why it can't be made
+  pub fn record_tool_call(&mut self, name: &str, max_calls: Option<usize>) -> Option<String> {
+    let calls = self.tool_calls.entry(name.to_string()).or_default();
+    match max_calls.or(self.limits.max_tool_calls) {
+      Some(max) if *calls >= max => Some(format!("{} reached its limit of {} calls", name, max)),
+      _ => {
+        *calls += 1;
+        None
+      }
+    }
+  }
+
+  pub fn remaining_time(&self) -> Option<Duration> {
+    self
+      .limits
+      .timeout
+      .map(|timeout| Duration::from_secs(timeout).saturating_sub(self.started.elapsed()))
+  }
+
+  // Bounds a request to the model by the time that is left. Running out of it ends the run, as
+  // there's no time left for a wrap-up turn either.
+  pub async fn within_time<T>(&self, request: impl Future<Output = Result<T>>) -> Result<T> {
+    match self.remaining_time() {
+      Some(remaining) => tokio::time::timeout(remaining, request)
+        .await
+        .unwrap_or_else(|_| {
+          Err(anyhow::anyhow!(
+            "Ran out of time after {} seconds, waiting for the model",
+            self.started.elapsed().as_secs()
+          ))
+          .context(ExitStatus::BudgetExhausted)
+        }),
+      None => request.await,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_budget_limits() {
+    let limits = Limits {
+      max_turns: Some(3),
+      max_tool_calls: Some(2),
+      ..Default::default()
+    };
+    let mut budget = Budget::new(limits.merge(Limits {
+      max_total_tokens: Some(100),
+      ..Default::default()
+    }));
+    let mut usage = UsageTracker::new("gpt-4", &HashMap::new());
+
+    assert_eq!(budget.exhausted(2, &usage), None);
+    assert!(budget.exhausted(3, &usage).unwrap().contains("3 turns"));
+
+    usage.record(
+      "gpt-4",
+      crate::usage::TokenUsage {
+        prompt_tokens: 90,
+        completion_tokens: 10,
+        ..Default::default()
+      },
+    );
+    assert!(budget.exhausted(1, &usage).unwrap().contains("100 tokens"));
+    assert!(budget.request_exceeds(1, &usage).is_some());
+
+    assert_eq!(budget.record_tool_call("ls", None), None);
+    assert_eq!(budget.record_tool_call("ls", None), None);
+    assert!(budget.record_tool_call("ls", None).is_some());
+    assert_eq!(budget.record_tool_call("cat", Some(5)), None);
+    assert!(budget.record_tool_call("grep", Some(0)).is_some());
+  }
+
+  #[tokio::test]
+  async fn test_requests_are_bounded_by_the_timeout() {
+    let budget = Budget::new(Limits {
+      timeout: Some(0),
+      ..Default::default()
+    });
+    let stalled = budget.within_time(std::future::pending::<Result<()>>());
+    let error = stalled.await.unwrap_err();
+    assert_eq!(ExitStatus::of(&error), ExitStatus::BudgetExhausted);
+
+    let unbounded = Budget::new(Limits::default());
+    assert_eq!(unbounded.within_time(async { Ok(1) }).await.unwrap(), 1);
+  }
+}
@@ -2,15 +2,101 @@ use anyhow::Result;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+
+use crate::approval::{Approval, ApprovalConfig};
+use crate::budget::Limits;
+use crate::context::ContextConfig;
+use crate::llm_client::{ModelParams, ToolChoice};
+use crate::policy::{Policy, PolicyConfig};
+use crate::providers::{ProviderKind, ToolProtocol};
+use crate::redact::RedactionConfig;
+use crate::transform::Transform;
+use crate::usage::ModelPricing;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     #[serde(default = "default_shell")]
     pub shell: String,
+    pub system_prompt: Option<PromptSource>,
+    /// Named provider endpoints, used instead of the LLM_CLI_* variables
+    #[serde(default)]
+    pub providers: HashMap<String, ProviderProfile>,
+    /// The provider profile to use, unless --profile says otherwise
+    pub profile: Option<String>,
+    /// Profiles that take over a turn, in order, when the ones before are down or rate limited
+    #[serde(default)]
+    pub fallback: Vec<String>,
+    #[serde(default)]
+    pub model: ModelConfig,
+    pub tool_choice: Option<ToolChoice>,
+    /// How tools are offered to models, `text` for models without native function calling
+    #[serde(default)]
+    pub tool_protocol: ToolProtocol,
+    pub final_tool: Option<FinalTool>,
+    /// Prices per model name (or name prefix), in USD per million tokens
+    #[serde(default)]
+    pub pricing: HashMap<String, ModelPricing>,
+    #[serde(default)]
+    pub limits: Limits,
+    #[serde(default)]
+    pub context: ContextConfig,
+    #[serde(default)]
+    pub redaction: RedactionConfig,
+    #[serde(default)]
+    pub policy: PolicyConfig,
+    #[serde(default)]
+    pub approval: ApprovalConfig,
+    /// How many invalid tool calls in a row the model gets to correct before the run fails
+    #[serde(default = "default_tool_error_retries")]
+    pub tool_error_retries: usize,
+    /// Where the outputs of tools with max_inline_output are saved, the system's temp dir by
+    /// default
+    pub artifact_dir: Option<PathBuf>,
     pub tools: Vec<Tool>,
 }
 
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ModelConfig {
+    pub name: Option<String>,
+    #[serde(flatten)]
+    pub params: ModelParams,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProviderProfile {
+    #[serde(default)]
+    pub kind: ProviderKind,
+    pub endpoint: String,
+    pub model: Option<String>,
+    /// Overrides the top-level `tool_protocol` for this provider
+    pub tool_protocol: Option<ToolProtocol>,
+    /// `api-version` query parameter, required by Azure
+    pub api_version: Option<String>,
+    /// Environment variable holding the API token
+    pub token_env: Option<String>,
+    /// File holding the API token, read again when it expires
+    pub token_file: Option<PathBuf>,
+    /// Command printing the API token, run again when it expires
+    pub token_command: Option<String>,
+    /// Seconds a token from a file or command stays valid
+    pub token_ttl: Option<u64>,
+    /// Header carrying the token, instead of the provider's usual one (e.g. `api-key` for Azure)
+    pub auth_header: Option<String>,
+    /// Prefix of the token in the header, `Bearer` by default for `Authorization`
+    pub auth_scheme: Option<String>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+/// Prompt text given either inline or as a path to a file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum PromptSource {
+    Inline(String),
+    File { file: PathBuf },
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Tool {
     pub name: String,
@@ -18,6 +104,38 @@ pub struct Tool {
     pub input_schema: Vec<JsonSchema>,
     pub command: String,
     pub shell: Option<String>,
+    /// Overrides limits.max_tool_calls for this tool
+    pub max_calls: Option<usize>,
+    /// Outputs longer than this many characters are saved to a file, and the model gets the
+    /// first and last lines with a handle to read the rest
+    pub max_inline_output: Option<usize>,
+    /// Exit codes that don't mean the tool failed, `[0]` by default (grep exits with 1 when
+    /// nothing matches)
+    #[serde(default = "default_ok_exit_codes")]
+    pub ok_exit_codes: Vec<i32>,
+    /// How a successful outcome is shown to the model, a template with `{{stdout}}`,
+    /// `{{stderr}}`, `{{exit_code}}` and `{{duration_ms}}`
+    pub output_template: Option<String>,
+    /// How a failed outcome is shown to the model, with the same variables
+    pub error_template: Option<String>,
+    /// Steps that clean up stdout and stderr before they're rendered, in order
+    #[serde(default, with = "serde_yaml::with::singleton_map_recursive")]
+    pub output: Vec<Transform>,
+    /// `required` to have every call approved before it runs
+    #[serde(default)]
+    pub approval: Approval,
+    /// Command run instead in a dry run, with the same arguments, for the model to get its
+    /// output rather than a placeholder
+    pub dry_run_output: Option<String>,
+}
+
+/// A tool without a command. Calling it with valid arguments ends the conversation, and the
+/// arguments are printed as the result.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FinalTool {
+    pub name: String,
+    pub description: String,
+    pub input_schema: Vec<JsonSchema>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -42,17 +160,42 @@ fn default_shell() -> String {
     "bash".to_string()
 }
 
+fn default_ok_exit_codes() -> Vec<i32> {
+    vec![0]
+}
+
+fn default_tool_error_retries() -> usize {
+    3
+}
+
 impl Config {
     pub fn from_file(path: &Path) -> Result<Self> {
         let contents = std::fs::read_to_string(path)?;
-        let config: Config = serde_yaml::from_str(&contents)?;
+        let mut config: Config = serde_yaml::from_str(&contents)?;
+
+        // Prompt and token files are relative to the config file, not the working directory
+        if let Some(dir) = path.parent() {
+            if let Some(PromptSource::File { file }) = &mut config.system_prompt {
+                *file = dir.join(&*file);
+            }
+            for profile in config.providers.values_mut() {
+                if let Some(file) = &mut profile.token_file {
+                    *file = dir.join(&*file);
+                }
+            }
+            if let Some(artifact_dir) = &mut config.artifact_dir {
+                *artifact_dir = dir.join(&*artifact_dir);
+            }
+        }
         
         // Validate patterns are valid regex
-        for tool in &config.tools {
-            for schema in &tool.input_schema {
+        let schemas = config.tools.iter().map(|tool| &tool.input_schema)
+            .chain(config.final_tool.iter().map(|tool| &tool.input_schema));
+        for input_schema in schemas {
+            for schema in input_schema {
                 match schema {
                     JsonSchema::Object { properties, .. } => {
-                        for (_, prop) in properties {
+                        for prop in properties.values() {
                             if let Some(pattern) = &prop.pattern {
                                 Regex::new(pattern)?;
                             }
@@ -62,8 +205,109 @@ impl Config {
             }
         }
         
+        Policy::new(&config.policy)?;
+        let approvers = [
+            config.approval.command.is_some(),
+            config.approval.pipe.is_some(),
+            config.approval.dir.is_some(),
+        ];
+        if approvers.iter().filter(|&&set| set).count() > 1 {
+            anyhow::bail!("approval has more than one of command, pipe and dir");
+        }
+        for pattern in &config.redaction.patterns {
+            Regex::new(pattern)?;
+        }
+        for tool in &config.tools {
+            for transform in &tool.output {
+                transform.validate().map_err(|e| anyhow::anyhow!("Tool {}: {}", tool.name, e))?;
+            }
+        }
+
+        config.validate_tool_choice(config.tool_choice.as_ref())?;
+        for name in config.profile.iter().chain(&config.fallback) {
+            config.provider(name)?;
+        }
+        for (name, profile) in &config.providers {
+            let sources = [
+                profile.token_env.is_some(),
+                profile.token_file.is_some(),
+                profile.token_command.is_some(),
+            ];
+            if sources.iter().filter(|&&set| set).count() > 1 {
+                anyhow::bail!("Provider {} has more than one of token_env, token_file and token_command", name);
+            }
+        }
+        if let Some(final_tool) = &config.final_tool
+            && config.tools.iter().any(|tool| tool.name == final_tool.name)
+        {
+            anyhow::bail!("Final tool {} has the same name as a tool", final_tool.name);
+        }
+        if config.spills_output() {
+            let builtins = [crate::artifacts::READ_OUTPUT, crate::artifacts::GREP_OUTPUT];
+            if let Some(tool) = config.tools.iter().find(|tool| builtins.contains(&tool.name.as_str())) {
+                anyhow::bail!("Tool {} has the name of a builtin tool", tool.name);
+            }
+        }
+
         Ok(config)
     }
+
+    // Whether any tool's output can be saved, which brings in the builtins to read it
+    pub fn spills_output(&self) -> bool {
+        self.tools.iter().any(|tool| tool.max_inline_output.is_some())
+    }
+
+    pub fn provider(&self, name: &str) -> Result<&ProviderProfile> {
+        self.providers
+            .get(name)
+            .ok_or_else(|| anyhow::anyhow!("Unknown provider profile: {}", name))
+    }
+
+    pub fn validate_tool_choice(&self, tool_choice: Option<&ToolChoice>) -> Result<()> {
+        if let Some(ToolChoice::Tool(name)) = tool_choice {
+            let is_final = self.final_tool.as_ref().is_some_and(|tool| &tool.name == name);
+            if !is_final && !self.tools.iter().any(|tool| &tool.name == name) {
+                anyhow::bail!("tool_choice refers to unknown tool {}", name);
+            }
+        }
+        Ok(())
+    }
+
+    /// The tool choice for the turns after the model made the calls a forced choice asked for.
+    /// Forcing a tool on every turn would never end the run, unless it's the final tool, so the
+    /// model is free to choose again.
+    pub fn tool_choice_after_calls(&self, tool_choice: Option<ToolChoice>) -> Option<ToolChoice> {
+        let final_tool = self.final_tool.as_ref().map(|tool| tool.name.as_str());
+        match tool_choice {
+            Some(ToolChoice::Required) if final_tool.is_none() => Some(ToolChoice::Auto),
+            Some(ToolChoice::Tool(name)) if final_tool != Some(name.as_str()) => Some(ToolChoice::Auto),
+            tool_choice => tool_choice,
+        }
+    }
+}
+
+impl PromptSource {
+    /// Interprets a command line value, where a leading `@` names a file to read
+    pub fn from_arg(arg: &str) -> Self {
+        match arg.strip_prefix('@') {
+            Some(path) => PromptSource::File { file: PathBuf::from(path) },
+            None => PromptSource::Inline(arg.to_string()),
+        }
+    }
+
+    pub fn load(&self) -> Result<String> {
+        match self {
+            PromptSource::Inline(text) => Ok(text.clone()),
+            PromptSource::File { file } => std::fs::read_to_string(file)
+                .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", file.display(), e)),
+        }
+    }
+}
+
+impl FinalTool {
+    pub fn validate_input(&self, input: &serde_json::Value) -> Result<()> {
+        validate_input(&self.input_schema, input)
+    }
 }
 
 impl Tool {
@@ -72,36 +316,7 @@ impl Tool {
     }
     
     pub fn validate_input(&self, input: &serde_json::Value) -> Result<()> {
-        for schema in &self.input_schema {
-            match schema {
-                JsonSchema::Object { properties, required } => {
-                    let obj = input.as_object()
-                        .ok_or_else(|| anyhow::anyhow!("Input must be an object"))?;
-                    
-                    // Check required fields
-                    for req in required {
-                        if !obj.contains_key(req) {
-                            anyhow::bail!("Missing required field: {}", req);
-                        }
-                    }
-                    
-                    // Validate each property
-                    for (name, value) in obj {
-                        if let Some(prop) = properties.get(name) {
-                            if let Some(pattern) = &prop.pattern {
-                                let regex = Regex::new(pattern)?;
-                                let str_value = value.as_str()
-                                    .ok_or_else(|| anyhow::anyhow!("Property {} must be a string", name))?;
-                                if !regex.is_match(str_value) {
-                                    anyhow::bail!("Property {} doesn't match pattern {}", name, pattern);
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-        }
-        Ok(())
+        validate_input(&self.input_schema, input)
     }
     
     pub fn build_command(&self, input: &serde_json::Value) -> Result<String> {
@@ -127,6 +342,37 @@ impl Tool {
     }
 }
 
+fn validate_input(input_schema: &[JsonSchema], input: &serde_json::Value) -> Result<()> {
+    for schema in input_schema {
+        match schema {
+            JsonSchema::Object { properties, required } => {
+                let obj = input.as_object()
+                    .ok_or_else(|| anyhow::anyhow!("Input must be an object"))?;
+                
+                // Check required fields
+                for req in required {
+                    if !obj.contains_key(req) {
+                        anyhow::bail!("Missing required field: {}", req);
+                    }
+                }
+                
+                // Validate each property
+                for (name, value) in obj {
+                    if let Some(pattern) = properties.get(name).and_then(|p| p.pattern.as_ref()) {
+                        let regex = Regex::new(pattern)?;
+                        let str_value = value.as_str()
+                            .ok_or_else(|| anyhow::anyhow!("Property {} must be a string", name))?;
+                        if !regex.is_match(str_value) {
+                            anyhow::bail!("Property {} doesn't match pattern {}", name, pattern);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -152,8 +398,152 @@ tools:
         assert_eq!(config.shell, "bash");
         assert_eq!(config.tools.len(), 1);
         assert_eq!(config.tools[0].name, "echo");
+        assert!(config.system_prompt.is_none());
+        assert!(config.model.name.is_none());
+        assert_eq!(config.tool_error_retries, 3);
+    }
+
+    #[test]
+    fn test_load_config_with_output_pipeline() {
+        let path = std::env::temp_dir().join(format!("llmcli-config-test-{}.yaml", std::process::id()));
+        let yaml = r#"
+tools:
+  - name: "list"
+    description: "List items"
+    input_schema: []
+    command: "cat items.json"
+    output:
+      - jq: ".items[] | .name"
+      - jq: "keys"
+"#;
+        std::fs::write(&path, yaml).unwrap();
+        let config = Config::from_file(&path);
+        std::fs::write(&path, yaml.replace("keys", ".items[")).unwrap();
+        let invalid = Config::from_file(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.unwrap().tools[0].output.len(), 2);
+        assert!(invalid.unwrap_err().to_string().contains("Tool list"));
+    }
+
+    #[test]
+    fn test_parse_model_params() {
+        let yaml = r#"
+model:
+  name: "gpt-4.1"
+  temperature: 0
+  seed: 42
+  stop: ["END"]
+  reasoning_effort: low
+pricing:
+  gpt-4.1: { input: 2.0, cached_input: 0.5, output: 8.0 }
+tools: []
+"#;
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(config.model.name.as_deref(), Some("gpt-4.1"));
+        assert_eq!(config.pricing["gpt-4.1"].cached_input, Some(0.5));
+        assert_eq!(config.model.params.temperature, Some(0.0));
+        assert_eq!(config.model.params.seed, Some(42));
+
+        let overrides = ModelParams {
+            temperature: Some(0.5),
+            ..Default::default()
+        };
+        let params = config.model.params.merge(overrides);
+        assert_eq!(params.temperature, Some(0.5));
+        assert_eq!(params.stop, vec!["END".to_string()]);
+    }
+
+    #[test]
+    fn test_parse_providers() {
+        let yaml = r#"
+providers:
+  openai:
+    endpoint: https://api.openai.com/v1/chat/completions
+    token_env: OPENAI_API_KEY
+  claude:
+    kind: anthropic
+    endpoint: https://api.anthropic.com/v1/messages
+    model: claude-sonnet-4-5
+    token_command: vault read -field=key secret/anthropic
+    token_ttl: 3600
+    auth_header: x-api-key
+    headers:
+      anthropic-beta: interleaved-thinking-2025-05-14
+profile: openai
+fallback: [claude]
+tools: []
+"#;
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(config.provider("openai").unwrap().kind, ProviderKind::OpenAi);
+        let claude = config.provider("claude").unwrap();
+        assert_eq!(claude.kind, ProviderKind::Anthropic);
+        assert_eq!(claude.model.as_deref(), Some("claude-sonnet-4-5"));
+        assert_eq!(claude.token_ttl, Some(3600));
+        assert_eq!(claude.auth_header.as_deref(), Some("x-api-key"));
+        assert_eq!(config.fallback, vec!["claude".to_string()]);
+        assert!(config.provider("missing").is_err());
+    }
+
+    #[test]
+    fn test_parse_system_prompt() {
+        let inline: Config = serde_yaml::from_str("system_prompt: Be brief\ntools: []").unwrap();
+        assert!(matches!(inline.system_prompt, Some(PromptSource::Inline(ref text)) if text == "Be brief"));
+
+        let file: Config = serde_yaml::from_str("system_prompt:\n  file: system.md\ntools: []").unwrap();
+        assert!(matches!(file.system_prompt, Some(PromptSource::File { .. })));
+
+        assert!(matches!(PromptSource::from_arg("@system.md"), PromptSource::File { .. }));
+        assert!(matches!(PromptSource::from_arg("Be brief"), PromptSource::Inline(_)));
     }
     
+    #[test]
+    fn test_parse_tool_choice_and_final_tool() {
+        let yaml = r#"
+tool_choice: required
+final_tool:
+  name: "submit"
+  description: "Submit the answer"
+  input_schema:
+    - type: object
+      properties:
+        answer:
+          type: string
+          description: "The answer"
+      required:
+        - answer
+tools: []
+"#;
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(config.tool_choice, Some(ToolChoice::Required));
+        let final_tool = config.final_tool.as_ref().unwrap();
+        assert!(final_tool.validate_input(&serde_json::json!({ "answer": "42" })).is_ok());
+        assert!(final_tool.validate_input(&serde_json::json!({})).is_err());
+
+        assert!(config.validate_tool_choice(Some(&ToolChoice::Tool("submit".to_string()))).is_ok());
+        assert!(config.validate_tool_choice(Some(&ToolChoice::Tool("missing".to_string()))).is_err());
+
+        // With a final tool, calling it is what ends the run, so the choice stays forced
+        assert_eq!(config.tool_choice_after_calls(Some(ToolChoice::Required)), Some(ToolChoice::Required));
+        let submit = ToolChoice::Tool("submit".to_string());
+        assert_eq!(config.tool_choice_after_calls(Some(submit.clone())), Some(submit));
+    }
+
+    #[test]
+    fn test_forced_tool_choice_applies_to_one_turn() {
+        let config: Config = serde_yaml::from_str("tool_choice: required\ntools: []").unwrap();
+        let first = config.tool_choice.clone();
+        assert_eq!(first, Some(ToolChoice::Required));
+        let second = config.tool_choice_after_calls(first);
+        assert_eq!(second, Some(ToolChoice::Auto));
+        assert_eq!(
+            config.tool_choice_after_calls(Some(ToolChoice::Tool("lookup".to_string()))),
+            Some(ToolChoice::Auto)
+        );
+        assert_eq!(config.tool_choice_after_calls(Some(ToolChoice::None)), Some(ToolChoice::None));
+        assert_eq!(config.tool_choice_after_calls(None), None);
+    }
+
     #[test]
     fn test_validate_input() {
         let tool = Tool {
@@ -171,6 +561,14 @@ tools:
             }],
             command: "test".to_string(),
             shell: None,
+            max_calls: None,
+            max_inline_output: None,
+            ok_exit_codes: vec![0],
+            output_template: None,
+            error_template: None,
+            output: vec![],
+            approval: Approval::Never,
+            dry_run_output: None,
         };
         
         let valid_input = serde_json::json!({
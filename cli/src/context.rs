@@ -0,0 +1,237 @@
+use anyhow::Result;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::llm_client::{
+  ClientChain, LlmRequest, Message, ModelParams, StreamEvent, ToolChoice, TurnResponse,
+};
+use crate::tokens::Tokenizer;
+
+// Turns at the end of the conversation that are never compacted, unless configured otherwise
+const KEEP_TURNS: usize = 4;
+
+// Tool outputs shorter than this aren't worth eliding
+const MIN_ELIDED_LENGTH: usize = 200;
+
+const SUMMARY_PROMPT: &str = "You compact the history of a task carried out by an AI assistant \
+with the help of tools, so it can continue with less context. Summarise the conversation below: \
+what was found out, what was done and changed, which tool calls succeeded or failed and why, and \
+what remains to be done. Keep file names, identifiers, numbers and error messages that may be \
+needed later. Reply with the summary only.";
+
+// How much of the model's context window the conversation may take up, set in the config file's
+// `context:` block and overridable from the CLI
+#[derive(Debug, Clone, Default, Serialize, Deserialize, clap::Args)]
+pub struct ContextConfig {
+  /// Tokens the conversation, with the tool definitions, may take up before it's compacted
+  #[arg(
+    id = "max_context_tokens",
+    long = "max-context-tokens",
+    value_name = "MAX_CONTEXT_TOKENS"
+  )]
+  pub max_tokens: Option<u64>,
+
+  /// Most recent turns that are kept whole when compacting (default 4)
+  #[arg(long)]
+  pub keep_turns: Option<usize>,
+}
+
+impl ContextConfig {
+  pub fn merge(self, overrides: ContextConfig) -> ContextConfig {
+    ContextConfig {
+      max_tokens: overrides.max_tokens.or(self.max_tokens),
+      keep_turns: overrides.keep_turns.or(self.keep_turns),
+    }
+  }
+
+  // Where the turns that are kept whole start. Turns start with the model's message, so tool
+  // calls and their results are never split up.
+  pub fn recent_start(&self, messages: &[Message]) -> usize {
+    let keep_turns = self.keep_turns.unwrap_or(KEEP_TURNS).max(1);
+    messages
+      .iter()
+      .enumerate()
+      .filter(|(_, message)| matches!(message, Message::Assistant { .. }))
+      .map(|(i, _)| i)
+      .rev()
+      .nth(keep_turns - 1)
+      .unwrap_or(messages.len())
+  }
+}
+
+// Replaces the outputs of tool calls before `end` with a note, oldest first, until the messages
+// are within `max_tokens`. Returns how many were elided.
+pub fn elide_tool_outputs(
+  tokenizer: Tokenizer,
+  messages: &mut [Message],
+  end: usize,
+  max_tokens: u64,
+) -> usize {
+  let mut tokens = tokenizer.count_messages(messages);
+  let mut elided = 0;
+  for message in messages.iter_mut().take(end) {
+    if tokens <= max_tokens {
+      break;
+    }
+    if let Message::Tool { content, .. } = message
+      && content.len() >= MIN_ELIDED_LENGTH
+    {
+      let note = format!(
+        "[Output elided to save context, it was {} characters long]",
+        content.len()
+      );
+      tokens = (tokens + tokenizer.count(&note)).saturating_sub(tokenizer.count(content));
+      *content = note;
+      elided += 1;
+    }
+  }
+  elided
+}
+
+// Where the messages that can be summarised start: after the system prompt and the task
+pub fn summary_start(messages: &[Message]) -> usize {
+  messages
+    .iter()
+    .position(|message| matches!(message, Message::User { .. }))
+    .map_or(messages.len(), |i| i + 1)
+}
+
+// Asks the model for a summary of `messages`, with a request of its own. Returns the response
+// and the model that gave it.
+pub async fn summarize(
+  client: &ClientChain,
+  model: &str,
+  messages: &[Message],
+) -> Result<(TurnResponse, String)> {
+  let request = LlmRequest {
+    messages: vec![
+      Message::System {
+        content: SUMMARY_PROMPT.to_string(),
+      },
+      Message::User {
+        content: transcript(messages),
+      },
+    ],
+    tools: Vec::new(),
+    model: model.to_string(),
+    stream: true,
+    params: ModelParams::default(),
+    tool_choice: Some(ToolChoice::None),
+    output_schema: None,
+  };
+
+  let completion = client.stream_completion(&request).await?;
+  let mut stream = completion.stream;
+  let mut response = TurnResponse::default();
+  while let Some(event) = stream.next().await {
+    match event? {
+      StreamEvent::Chunk(chunk) => {
+        response.add_chunk(chunk);
+      }
+      StreamEvent::Done => break,
+    }
+  }
+  if response.text.trim().is_empty() {
+    anyhow::bail!("The model returned an empty summary");
+  }
+  Ok((response, completion.model))
+}
+
+// The summarised messages are replaced by a single user message
+pub fn summary_message(summary: &str) -> Message {
+  Message::User {
+    content: format!(
+      "The earlier part of this conversation was compacted to save context. Summary:\n\n{}",
+      summary.trim()
+    ),
+  }
+}
+
+fn transcript(messages: &[Message]) -> String {
+  let mut tool_names = HashMap::new();
+  let mut transcript = String::new();
+  for message in messages {
+    match message {
+      Message::System { content } => transcript.push_str(&format!("System: {}\n\n", content)),
+      Message::User { content } => transcript.push_str(&format!("User: {}\n\n", content)),
+      Message::Assistant {
+        content,
+        tool_calls,
+        ..
+      } => {
+        if let Some(content) = content.as_deref().filter(|content| !content.is_empty()) {
+          transcript.push_str(&format!("Assistant: {}\n\n", content));
+        }
+        for call in tool_calls.iter().flatten() {
+          tool_names.insert(call.id.as_str(), call.function.name.as_str());
+          transcript.push_str(&format!(
+            "Assistant called {} with {}\n\n",
+            call.function.name, call.function.arguments
+          ));
+        }
+      }
+      Message::Tool {
+        tool_call_id,
+        content,
+      } => transcript.push_str(&format!(
+        "Result of {}: {}\n\n",
+        tool_names.get(tool_call_id.as_str()).unwrap_or(&"the call"),
+        content
+      )),
+    }
+  }
+  transcript
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::llm_client::{ToolCall, ToolCallFunction};
+
+  #[test]
+  fn test_elide_keeps_recent_turns() {
+    let turn = |id: &str| {
+      vec![
+        Message::Assistant {
+          content: None,
+          reasoning: vec![],
+          tool_calls: Some(vec![ToolCall {
+            id: id.to_string(),
+            tool_type: "function".to_string(),
+            function: ToolCallFunction {
+              name: "read_file".to_string(),
+              arguments: serde_json::json!({ "file_path": "main.rs" }),
+            },
+          }]),
+        },
+        Message::Tool {
+          tool_call_id: id.to_string(),
+          content: "x".repeat(1000),
+        },
+      ]
+    };
+    let mut messages = vec![Message::User {
+      content: "Review main.rs".to_string(),
+    }];
+    for id in ["a", "b", "c"] {
+      messages.extend(turn(id));
+    }
+
+    let config = ContextConfig {
+      max_tokens: Some(100),
+      keep_turns: Some(1),
+    };
+    let end = config.recent_start(&messages);
+    assert_eq!(end, 5);
+    assert_eq!(summary_start(&messages), 1);
+    assert_eq!(
+      elide_tool_outputs(Tokenizer::O200k, &mut messages, end, 200),
+      2
+    );
+    assert!(
+      matches!(&messages[2], Message::Tool { content, .. } if content.starts_with("[Output elided"))
+    );
+    assert!(matches!(&messages[6], Message::Tool { content, .. } if content.len() == 1000));
+  }
+}
@@ -0,0 +1,67 @@
+use std::fmt;
+use std::process::ExitCode;
+
+// The process exit codes, documented in the README. Errors are classified by attaching one of
+// these as context, e.g. `.context(ExitStatus::Config)`; anything unclassified is a plain failure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+  Success = 0,
+  Failure = 1,
+  Config = 2,
+  Provider = 3,
+  BudgetExhausted = 4,
+  ValidationFailed = 5,
+  PolicyViolation = 6,
+  InvalidToolCalls = 7,
+  Interrupted = 130,
+}
+
+impl ExitStatus {
+  pub fn of(error: &anyhow::Error) -> Self {
+    error
+      .downcast_ref::<ExitStatus>()
+      .copied()
+      .unwrap_or(ExitStatus::Failure)
+  }
+}
+
+impl fmt::Display for ExitStatus {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str(match self {
+      ExitStatus::Success => "success",
+      ExitStatus::Failure => "failure",
+      ExitStatus::Config => "configuration error",
+      ExitStatus::Provider => "provider error",
+      ExitStatus::BudgetExhausted => "budget exhausted",
+      ExitStatus::ValidationFailed => "final answer validation failed",
+      ExitStatus::PolicyViolation => "tool policy violation",
+      ExitStatus::InvalidToolCalls => "too many invalid tool calls",
+      ExitStatus::Interrupted => "interrupted",
+    })
+  }
+}
+
+impl From<ExitStatus> for ExitCode {
+  fn from(status: ExitStatus) -> Self {
+    ExitCode::from(status as u8)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use anyhow::Context;
+
+  #[test]
+  fn test_exit_status_of_error() {
+    let error = Err::<(), _>(anyhow::anyhow!("missing field `tools`"))
+      .context(ExitStatus::Config)
+      .context("Failed to load config.yaml")
+      .unwrap_err();
+    assert_eq!(ExitStatus::of(&error), ExitStatus::Config);
+    assert_eq!(
+      ExitStatus::of(&anyhow::anyhow!("disk full")),
+      ExitStatus::Failure
+    );
+  }
+}
@@ -1,22 +1,31 @@
-use anyhow::Result;
-use eventsource_stream::Eventsource;
+use anyhow::{Context, Result};
 use futures::Stream;
-use futures::StreamExt;
+use reqwest::StatusCode;
 use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
-use reqwest_middleware::ClientBuilder;
-use reqwest_retry::{RetryTransientMiddleware, policies::ExponentialBackoff};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::env;
 use std::pin::Pin;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::auth::{Credentials, TokenSource};
+use crate::config::ProviderProfile;
+use crate::providers::{ProviderKind, ToolProtocol};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "role", rename_all = "snake_case")]
 pub enum Message {
+  System {
+    content: String,
+  },
   User {
     content: String,
   },
   Assistant {
     content: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    reasoning: Vec<Reasoning>,
     tool_calls: Option<Vec<ToolCall>>,
   },
   Tool {
@@ -61,25 +70,121 @@ fn default_call_name() -> String {
   "".to_string()
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ToolResult {
-  pub tool_call_id: String,
-  pub content: String,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(untagged)]
-pub enum LlmResponse {
-  Text { content: String },
-  ToolCall { tool_calls: Vec<ToolCall> },
-}
-
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LlmRequest {
   pub messages: Vec<Message>,
   pub tools: Vec<ToolDefinition>,
   pub model: String,
   pub stream: bool,
+  // Mapped onto each provider's own parameter names
+  #[serde(skip)]
+  pub params: ModelParams,
+  #[serde(skip)]
+  pub tool_choice: Option<ToolChoice>,
+  // JSON schema the response has to follow
+  #[serde(skip)]
+  pub output_schema: Option<serde_json::Value>,
+}
+
+// Whether and which tools the model has to call: `auto`, `none`, `required` or a tool name
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum ToolChoice {
+  Auto,
+  None,
+  Required,
+  Tool(String),
+}
+
+impl From<String> for ToolChoice {
+  fn from(s: String) -> Self {
+    match s.as_str() {
+      "auto" => ToolChoice::Auto,
+      "none" => ToolChoice::None,
+      "required" => ToolChoice::Required,
+      _ => ToolChoice::Tool(s),
+    }
+  }
+}
+
+impl From<ToolChoice> for String {
+  fn from(choice: ToolChoice) -> Self {
+    match choice {
+      ToolChoice::Auto => "auto".to_string(),
+      ToolChoice::None => "none".to_string(),
+      ToolChoice::Required => "required".to_string(),
+      ToolChoice::Tool(name) => name,
+    }
+  }
+}
+
+impl std::str::FromStr for ToolChoice {
+  type Err = std::convert::Infallible;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    Ok(ToolChoice::from(s.to_string()))
+  }
+}
+
+// Sampling parameters, set in the config file's `model:` block and overridable from the CLI
+#[derive(Debug, Clone, Default, Serialize, Deserialize, clap::Args)]
+pub struct ModelParams {
+  /// Sampling temperature
+  #[arg(long)]
+  pub temperature: Option<f64>,
+
+  /// Nucleus sampling probability mass
+  #[arg(long)]
+  pub top_p: Option<f64>,
+
+  /// Maximum number of tokens to generate per response
+  #[arg(long)]
+  pub max_tokens: Option<u32>,
+
+  /// Stop sequence (can be repeated)
+  #[arg(long)]
+  #[serde(default)]
+  pub stop: Vec<String>,
+
+  /// Seed for deterministic sampling, where the provider supports it
+  #[arg(long)]
+  pub seed: Option<i64>,
+
+  /// Reasoning effort for reasoning models
+  #[arg(long, value_enum)]
+  pub reasoning_effort: Option<ReasoningEffort>,
+
+  /// Token budget for extended thinking, where the provider supports it
+  #[arg(long)]
+  pub thinking_budget: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ReasoningEffort {
+  Minimal,
+  Low,
+  Medium,
+  High,
+}
+
+impl ModelParams {
+  // Values set in `overrides` take precedence
+  pub fn merge(self, overrides: ModelParams) -> ModelParams {
+    ModelParams {
+      temperature: overrides.temperature.or(self.temperature),
+      top_p: overrides.top_p.or(self.top_p),
+      max_tokens: overrides.max_tokens.or(self.max_tokens),
+      stop: if overrides.stop.is_empty() {
+        self.stop
+      } else {
+        overrides.stop
+      },
+      seed: overrides.seed.or(self.seed),
+      reasoning_effort: overrides.reasoning_effort.or(self.reasoning_effort),
+      thinking_budget: overrides.thinking_budget.or(self.thinking_budget),
+    }
+  }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -96,17 +201,71 @@ pub struct FunctionDefinition {
   pub parameters: serde_json::Value, // JSON schema for parameters
 }
 
+pub type EventStream = Pin<Box<dyn Stream<Item = Result<StreamEvent>> + Send>>;
+
+// Retries of requests that fail transiently, and the longest Retry-After we're willing to wait
+const MAX_RETRIES: u32 = 3;
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+// Reasoning the model did before answering. Anthropic requires its signed thinking blocks to be
+// sent back along with tool results, other providers only get to see the answers.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Reasoning {
+  pub text: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub signature: Option<String>,
+  // Encrypted reasoning, which can only be passed back as is
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub redacted: Option<String>,
+}
+
+impl Reasoning {
+  // Signed and redacted reasoning blocks are complete
+  fn is_open(&self) -> bool {
+    self.signature.is_none() && self.redacted.is_none()
+  }
+}
+
 pub struct LlmClient {
-  client: reqwest_middleware::ClientWithMiddleware,
+  pub name: String,
+  client: reqwest::Client,
   endpoint: String,
   headers: HeaderMap,
+  credentials: Option<Credentials>,
+  api_version: Option<String>,
+  provider: ProviderKind,
+  tool_protocol: ToolProtocol,
+  // Replaces the requested model, for fallbacks to other providers
+  model: Option<String>,
+  max_retries: u32,
+}
+
+// An error response from the API, kept apart so outages can be told from other failures
+#[derive(Debug)]
+pub struct ApiError {
+  pub status: StatusCode,
+  pub body: String,
 }
 
+impl std::fmt::Display for ApiError {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    write!(f, "LLM API error: {} - {}", self.status, self.body)
+  }
+}
+
+impl std::error::Error for ApiError {}
+
 impl LlmClient {
-  pub fn from_env() -> Result<Self> {
+  // Token commands run with `shell`, like tools, and without the variables in `hidden_env`
+  pub fn from_env(shell: &str, hidden_env: &[String]) -> Result<Self> {
     let endpoint =
       env::var("LLM_CLI_ENDPOINT").map_err(|_| anyhow::anyhow!("LLM_CLI_ENDPOINT not set"))?;
 
+    let provider = match env::var("LLM_CLI_PROVIDER") {
+      Ok(provider) => provider.parse()?,
+      Err(_) => ProviderKind::default(),
+    };
+
     let mut headers = HeaderMap::new();
 
     // Add custom headers from environment
@@ -119,67 +278,284 @@ impl LlmClient {
       }
     }
 
-    // Add token if provided
-    if let Ok(token) = env::var("LLM_CLI_TOKEN") {
+    let source = match (
+      env::var("LLM_CLI_TOKEN"),
+      env::var_os("LLM_CLI_TOKEN_FILE"),
+      env::var("LLM_CLI_TOKEN_COMMAND"),
+    ) {
+      (Ok(token), _, _) => Some(TokenSource::Static(token)),
+      (_, Some(file), _) => Some(TokenSource::File(file.into())),
+      (_, _, Ok(command)) => Some(TokenSource::Command {
+        command,
+        shell: shell.to_string(),
+        hidden_env: hidden_env.to_vec(),
+      }),
+      _ => None,
+    };
+    let credentials = source.map(|source| Credentials::new(source, provider));
+    Ok(
+      Self::new("env", provider, endpoint, headers, credentials)
+        .with_api_version(env::var("LLM_CLI_API_VERSION").ok()),
+    )
+  }
+
+  pub fn from_profile(
+    name: &str,
+    profile: &ProviderProfile,
+    shell: &str,
+    hidden_env: &[String],
+  ) -> Result<Self> {
+    let mut headers = HeaderMap::new();
+    for (key, value) in &profile.headers {
       headers.insert(
-        "Authorization",
-        HeaderValue::from_str(&format!("Bearer {}", token))?,
+        HeaderName::from_bytes(key.as_bytes())?,
+        HeaderValue::from_str(value)?,
       );
     }
 
-    let retry_policy = ExponentialBackoff::builder().build_with_max_retries(3);
-    let client = ClientBuilder::new(reqwest::Client::new())
-      .with(RetryTransientMiddleware::new_with_policy(retry_policy))
-      .build();
+    let source = match (
+      &profile.token_env,
+      &profile.token_file,
+      &profile.token_command,
+    ) {
+      (Some(var), _, _) => Some(TokenSource::Static(env::var(var).map_err(|_| {
+        anyhow::anyhow!("{} not set, it holds the token of provider {}", var, name)
+      })?)),
+      (_, Some(file), _) => Some(TokenSource::File(file.clone())),
+      (_, _, Some(command)) => Some(TokenSource::Command {
+        command: command.clone(),
+        shell: shell.to_string(),
+        hidden_env: hidden_env.to_vec(),
+      }),
+      (None, None, None) => None,
+    };
+    let credentials = source.map(|source| {
+      Credentials::new(source, profile.kind)
+        .with_ttl(profile.token_ttl.map(Duration::from_secs))
+        .with_header(profile.auth_header.clone(), profile.auth_scheme.clone())
+    });
+
+    Ok(
+      Self::new(
+        name,
+        profile.kind,
+        profile.endpoint.clone(),
+        headers,
+        credentials,
+      )
+      .with_api_version(profile.api_version.clone()),
+    )
+  }
 
-    Ok(Self {
-      client,
+  fn new(
+    name: &str,
+    provider: ProviderKind,
+    endpoint: String,
+    mut headers: HeaderMap,
+    credentials: Option<Credentials>,
+  ) -> Self {
+    provider.default_headers(&mut headers);
+
+    Self {
+      name: name.to_string(),
+      client: reqwest::Client::new(),
       endpoint,
       headers,
-    })
+      credentials,
+      api_version: provider.default_api_version().map(String::from),
+      provider,
+      tool_protocol: ToolProtocol::default(),
+      model: None,
+      max_retries: MAX_RETRIES,
+    }
+  }
+
+  pub fn with_tool_protocol(mut self, tool_protocol: ToolProtocol) -> Self {
+    self.tool_protocol = tool_protocol;
+    self
   }
 
-  pub async fn stream_completion(
-    &self,
-    request: LlmRequest,
-  ) -> Result<Pin<Box<dyn Stream<Item = Result<StreamEvent>> + Send>>> {
-    let body = serde_json::to_string(&request)?;
-    let response = self
-      .client
-      .post(&self.endpoint)
-      .headers(self.headers.clone())
-      .header("Content-Type", "application/json")
-      .body(body)
-      .send()
-      .await?;
-
-    if !response.status().is_success() {
-      let status = response.status();
-      let body = response.text().await?;
-      anyhow::bail!("LLM API error: {} - {}", status, body);
+  pub fn with_api_version(mut self, api_version: Option<String>) -> Self {
+    if api_version.is_some() {
+      self.api_version = api_version;
     }
+    self
+  }
+
+  pub fn with_model(mut self, model: Option<String>) -> Self {
+    self.model = model;
+    self
+  }
+
+  fn completion(&self, stream: EventStream, request: &LlmRequest) -> Completion {
+    Completion {
+      stream,
+      model: self.model.clone().unwrap_or_else(|| request.model.clone()),
+    }
+  }
 
-    let stream = response.bytes_stream().eventsource().map(|event| {
-      match event {
-        Ok(event) => {
-          // Parse SSE event data
-          let data = event.data;
-          if data == "[DONE]" {
-            tracing::debug!("Received done event");
-            Ok(StreamEvent::Done)
-          } else {
-            tracing::debug!("Received chunk data: {}", &data);
-            match serde_json::from_str::<StreamChunk>(&data) {
-              Ok(chunk) => Ok(StreamEvent::Chunk(chunk)),
-              Err(e) => Err(anyhow::anyhow!("Failed to parse chunk: {}", e)),
+  pub async fn stream_completion(&self, request: &LlmRequest) -> Result<EventStream> {
+    let mut body = self.tool_protocol.request_body(self.provider, request)?;
+    if let Some(model) = &self.model {
+      body["model"] = serde_json::json!(model);
+    }
+    let body = serde_json::to_string(&body)?;
+
+    let mut attempt = 0;
+    let mut refreshed = false;
+    loop {
+      let mut builder = self
+        .client
+        .post(&self.endpoint)
+        .headers(self.headers.clone())
+        .header("Content-Type", "application/json")
+        .body(body.clone());
+      if let Some(api_version) = &self.api_version {
+        builder = builder.query(&[("api-version", api_version)]);
+      }
+      if let Some(credentials) = &self.credentials {
+        let (name, value) = credentials
+          .header()
+          .await
+          .with_context(|| format!("Failed to get the token of {}", self.name))?;
+        builder = builder.header(name, value);
+      }
+      let result = builder.send().await;
+
+      // Transient failures are retried, waiting as long as the server asks us to
+      let delay = match result {
+        Ok(response) if response.status().is_success() => {
+          return Ok(self.tool_protocol.event_stream(self.provider, response));
+        }
+        // Short-lived tokens may be revoked or expire before their ttl is up
+        Ok(response)
+          if response.status() == StatusCode::UNAUTHORIZED
+            && !refreshed
+            && let Some(credentials) = &self.credentials
+            && credentials.refreshable() =>
+        {
+          warn!("{} rejected the token, fetching a new one", self.name);
+          credentials.invalidate();
+          refreshed = true;
+          continue;
+        }
+        Ok(response) => {
+          let status = response.status();
+          let retry_after = retry_after(response.headers());
+          let body = response.text().await.unwrap_or_default();
+          let error = anyhow::Error::from(ApiError { status, body });
+          if attempt >= self.max_retries || !is_transient(status) {
+            return Err(error);
+          }
+          match retry_after {
+            Some(delay) if delay > MAX_RETRY_AFTER => {
+              return Err(
+                error.context(format!("Retry-After of {}s is too long", delay.as_secs())),
+              );
             }
+            Some(delay) => delay,
+            None => backoff(attempt),
           }
         }
-        Err(e) => Err(anyhow::anyhow!("Stream error: {}", e)),
+        Err(e) if attempt < self.max_retries && (e.is_connect() || e.is_timeout()) => {
+          backoff(attempt)
+        }
+        Err(e) => return Err(e.into()),
+      };
+
+      attempt += 1;
+      warn!(
+        "Request to {} failed, retrying in {:.1}s ({}/{})",
+        self.name,
+        delay.as_secs_f32(),
+        attempt,
+        self.max_retries
+      );
+      tokio::time::sleep(delay).await;
+    }
+  }
+}
+
+// The primary client followed by its fallbacks, each taking over a turn while the ones before it
+// are down or rate limited
+pub struct ClientChain {
+  clients: Vec<LlmClient>,
+}
+
+// A turn's response stream, from whichever client of the chain took the turn
+pub struct Completion {
+  pub stream: EventStream,
+  // The model that answers, for pricing the turn
+  pub model: String,
+}
+
+impl ClientChain {
+  pub fn new(primary: LlmClient, fallbacks: Vec<LlmClient>) -> Self {
+    let mut clients = vec![primary];
+    clients.extend(fallbacks);
+    // Rather than wait for a client to recover, the next one takes over right away. Only the
+    // last one has no one to hand over to.
+    let last = clients.len() - 1;
+    for client in &mut clients[..last] {
+      client.max_retries = 0;
+    }
+    Self { clients }
+  }
+
+  pub async fn stream_completion(&self, request: &LlmRequest) -> Result<Completion> {
+    let (last, rest) = self
+      .clients
+      .split_last()
+      .expect("there is a primary client");
+    for client in rest {
+      match client.stream_completion(request).await {
+        Err(e) if is_unavailable(&e) => {
+          warn!(
+            "Provider {} is unavailable, falling back: {:#}",
+            client.name, e
+          )
+        }
+        result => return result.map(|stream| client.completion(stream, request)),
       }
-    });
+    }
+    let stream = last.stream_completion(request).await?;
+    Ok(last.completion(stream, request))
+  }
+}
+
+fn is_unavailable(error: &anyhow::Error) -> bool {
+  if let Some(error) = error.downcast_ref::<ApiError>() {
+    return is_transient(error.status);
+  }
+  error
+    .downcast_ref::<reqwest::Error>()
+    .is_some_and(|e| e.is_connect() || e.is_timeout())
+}
+
+fn is_transient(status: StatusCode) -> bool {
+  // 529 is Anthropic's "overloaded"
+  matches!(status.as_u16(), 408 | 429 | 500 | 502 | 503 | 504 | 529)
+}
+
+fn backoff(attempt: u32) -> Duration {
+  Duration::from_secs(1 << attempt)
+}
 
-    Ok(Box::pin(stream))
+// Retry-After is either seconds or an HTTP date, and some providers send milliseconds instead
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+  let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+  if let Some(ms) = header("retry-after-ms").and_then(|ms| ms.trim().parse::<f64>().ok()) {
+    // Values too large for a Duration are as good as forever
+    return Some(Duration::try_from_secs_f64(ms.max(0.0) / 1000.0).unwrap_or(Duration::MAX));
+  }
+  let value = header("retry-after")?.trim();
+  match value.parse::<u64>() {
+    Ok(seconds) => Some(Duration::from_secs(seconds)),
+    Err(_) => {
+      let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+      let seconds = (date.with_timezone(&chrono::Utc) - chrono::Utc::now()).num_seconds();
+      Some(Duration::from_secs(seconds.max(0) as u64))
+    }
   }
 }
 
@@ -191,23 +567,60 @@ pub enum StreamEvent {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StreamChunk {
+  #[serde(default)]
   pub choices: Vec<StreamChoice>,
+  pub usage: Option<Usage>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Usage {
+  #[serde(default)]
+  pub prompt_tokens: u64,
+  #[serde(default)]
+  pub completion_tokens: u64,
+  pub prompt_tokens_details: Option<PromptTokensDetails>,
+  pub completion_tokens_details: Option<CompletionTokensDetails>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PromptTokensDetails {
+  pub cached_tokens: Option<u64>,
+  // Tokens written to the cache, which Anthropic charges more for
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub cache_write_tokens: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CompletionTokensDetails {
+  pub reasoning_tokens: Option<u64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StreamChoice {
   pub delta: Option<Delta>,
+  // "stop", "length", "tool_calls" or "content_filter", in the last chunk
+  pub finish_reason: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Delta {
   pub content: Option<String>,
+  pub refusal: Option<String>,
+  // Servers disagree on the name of reasoning deltas
+  pub reasoning_content: Option<String>,
+  pub reasoning: Option<String>,
+  // Not part of the chunk format, these carry Anthropic's thinking signatures and redacted thinking
+  pub reasoning_signature: Option<String>,
+  pub redacted_reasoning: Option<String>,
   pub tool_calls: Option<Vec<ToolCallChunk>>,
 }
 
+// OpenAI sends the id and name first and then argument deltas by index, but compatible servers
+// may leave out the index, send whole calls at once or repeat them, and send arguments as objects
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ToolCallChunk {
-  pub index: usize,
+  #[serde(default)]
+  pub index: Option<usize>,
   pub id: Option<String>,
   pub function: ToolCallFunctionChunk,
 }
@@ -215,51 +628,215 @@ pub struct ToolCallChunk {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ToolCallFunctionChunk {
   pub name: Option<String>,
+  #[serde(default, deserialize_with = "deserialize_partial_arguments")]
   pub arguments: String, // Partial JSON
 }
 
+fn deserialize_partial_arguments<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+  D: serde::Deserializer<'de>,
+{
+  Ok(match serde_json::Value::deserialize(deserializer)? {
+    serde_json::Value::Null => String::new(),
+    serde_json::Value::String(arguments) => arguments,
+    arguments => arguments.to_string(),
+  })
+}
+
+// The model's response in one turn, accumulated from the stream's chunks
+#[derive(Debug, Default)]
+pub struct TurnResponse {
+  pub text: String,
+  pub refusal: Option<String>,
+  pub reasoning: Vec<Reasoning>,
+  pub tool_calls: Vec<ToolCall>,
+  // Arguments of each tool call as streamed, parsed once the turn is complete
+  pub tool_arguments: Vec<String>,
+  pub usage: Option<Usage>,
+  pub finish_reason: Option<String>,
+  // Position in `tool_calls` of each index the stream used
+  tool_indices: HashMap<usize, usize>,
+}
+
+// Text of a chunk, to show as it streams in
+#[derive(Debug, Default)]
+pub struct ChunkText {
+  pub reasoning: String,
+  pub answer: String,
+}
+
+impl TurnResponse {
+  pub fn add_chunk(&mut self, chunk: StreamChunk) -> ChunkText {
+    let mut shown = ChunkText::default();
+
+    // Providers report usage for the whole turn, possibly more than once as it grows
+    if chunk.usage.is_some() {
+      self.usage = chunk.usage;
+    }
+
+    for choice in chunk.choices {
+      if choice.finish_reason.is_some() {
+        self.finish_reason = choice.finish_reason;
+      }
+      let Some(delta) = choice.delta else {
+        continue;
+      };
+      if let Some(reasoning) = delta.reasoning_content.or(delta.reasoning) {
+        match self.reasoning.last_mut() {
+          Some(last) if last.is_open() => last.text.push_str(&reasoning),
+          _ => self.reasoning.push(Reasoning {
+            text: reasoning.clone(),
+            ..Default::default()
+          }),
+        }
+        shown.reasoning.push_str(&reasoning);
+      }
+      if let Some(signature) = delta.reasoning_signature {
+        match self.reasoning.last_mut() {
+          Some(last) if last.is_open() => last.signature = Some(signature),
+          _ => self.reasoning.push(Reasoning {
+            signature: Some(signature),
+            ..Default::default()
+          }),
+        }
+      }
+      if let Some(data) = delta.redacted_reasoning {
+        self.reasoning.push(Reasoning {
+          redacted: Some(data),
+          ..Default::default()
+        });
+      }
+      if let Some(content) = delta.content {
+        self.text.push_str(&content);
+        shown.answer.push_str(&content);
+      }
+      if let Some(refusal) = delta.refusal {
+        self.refusal.get_or_insert_default().push_str(&refusal);
+        shown.answer.push_str(&refusal);
+      }
+      for call in delta.tool_calls.into_iter().flatten() {
+        tracing::debug!("Received tool call: {:?}", &call);
+        self.add_tool_call_chunk(call);
+      }
+    }
+
+    shown
+  }
+
+  fn add_tool_call_chunk(&mut self, call: ToolCallChunk) {
+    let known = match call.index {
+      Some(index) => self.tool_indices.get(&index).copied(),
+      // Without one, a chunk continues the last call unless it names a new one
+      None => self
+        .tool_calls
+        .len()
+        .checked_sub(1)
+        .filter(|&last| call.function.name.is_none() || !is_json(&self.tool_arguments[last])),
+    };
+    // A different id means a new call, even where a server reuses the index
+    let position = match known {
+      Some(position)
+        if call
+          .id
+          .as_ref()
+          .is_none_or(|id| *id == self.tool_calls[position].id) =>
+      {
+        position
+      }
+      _ => {
+        self.tool_calls.push(ToolCall {
+          id: format!("call_{}", self.tool_calls.len()),
+          tool_type: "function".to_string(),
+          function: ToolCallFunction {
+            name: String::new(),
+            arguments: serde_json::Value::Null,
+          },
+        });
+        self.tool_arguments.push(String::new());
+        self.tool_calls.len() - 1
+      }
+    };
+    if let Some(index) = call.index {
+      self.tool_indices.insert(index, position);
+    }
+
+    let tool_call = &mut self.tool_calls[position];
+    if let Some(id) = call.id {
+      tool_call.id = id;
+    }
+    if let Some(name) = call.function.name.filter(|name| !name.is_empty()) {
+      tool_call.function.name = name;
+    }
+    // Servers that send whole arguments may send them again, rather than deltas
+    let arguments = &mut self.tool_arguments[position];
+    if is_json(arguments) && is_json(&call.function.arguments) {
+      *arguments = call.function.arguments;
+    } else {
+      arguments.push_str(&call.function.arguments);
+    }
+  }
+}
+
+fn is_json(text: &str) -> bool {
+  serde_json::from_str::<serde_json::Value>(text).is_ok()
+}
+
 impl crate::config::Tool {
   pub fn to_llm_definition(&self) -> ToolDefinition {
-    let mut parameters = serde_json::json!({});
-
-    for schema in &self.input_schema {
-      match schema {
-        crate::config::JsonSchema::Object {
-          properties,
-          required,
-        } => {
-          let mut schema_props = serde_json::Map::new();
-
-          for (name, prop) in properties {
-            let mut prop_def = serde_json::json!({
-                "type": prop.prop_type,
-                "description": prop.description,
-            });
-
-            if let Some(pattern) = &prop.pattern {
-              prop_def["pattern"] = serde_json::json!(pattern);
-            }
+    function_definition(&self.name, &self.description, &self.input_schema)
+  }
+}
 
-            schema_props.insert(name.clone(), prop_def);
-          }
+impl crate::config::FinalTool {
+  pub fn to_llm_definition(&self) -> ToolDefinition {
+    function_definition(&self.name, &self.description, &self.input_schema)
+  }
+}
 
-          parameters = serde_json::json!({
-              "type": "object",
-              "properties": schema_props,
-              "required": required,
+fn function_definition(
+  name: &str,
+  description: &str,
+  input_schema: &[crate::config::JsonSchema],
+) -> ToolDefinition {
+  let mut parameters = serde_json::json!({});
+
+  for schema in input_schema {
+    match schema {
+      crate::config::JsonSchema::Object {
+        properties,
+        required,
+      } => {
+        let mut schema_props = serde_json::Map::new();
+
+        for (name, prop) in properties {
+          let mut prop_def = serde_json::json!({
+              "type": prop.prop_type,
+              "description": prop.description,
           });
+
+          if let Some(pattern) = &prop.pattern {
+            prop_def["pattern"] = serde_json::json!(pattern);
+          }
+
+          schema_props.insert(name.clone(), prop_def);
         }
+
+        parameters = serde_json::json!({
+            "type": "object",
+            "properties": schema_props,
+            "required": required,
+        });
       }
     }
+  }
 
-    ToolDefinition {
-      function: FunctionDefinition {
-        name: self.name.clone(),
-        description: self.description.clone(),
-        parameters,
-      },
-      tool_type: "function".to_string(),
-    }
+  ToolDefinition {
+    function: FunctionDefinition {
+      name: name.to_string(),
+      description: description.to_string(),
+      parameters,
+    },
+    tool_type: "function".to_string(),
   }
 }
 
@@ -286,6 +863,14 @@ mod tests {
       }],
       command: "echo $param_message".to_string(),
       shell: None,
+      max_calls: None,
+      max_inline_output: None,
+      ok_exit_codes: vec![0],
+      output_template: None,
+      error_template: None,
+      output: vec![],
+      approval: crate::approval::Approval::Never,
+      dry_run_output: None,
     };
 
     let def = tool.to_llm_definition();
@@ -296,4 +881,108 @@ mod tests {
     assert!(params.get("properties").is_some());
     assert!(params.get("required").is_some());
   }
+
+  #[test]
+  fn test_turn_response_accumulates_reasoning() {
+    use super::{Delta, StreamChoice, StreamChunk, TurnResponse};
+
+    let chunk = |delta: Delta| StreamChunk {
+      choices: vec![StreamChoice {
+        delta: Some(delta),
+        finish_reason: None,
+      }],
+      usage: None,
+    };
+    let mut response = TurnResponse::default();
+    for delta in [
+      Delta {
+        reasoning_content: Some("Think".to_string()),
+        ..Default::default()
+      },
+      Delta {
+        reasoning: Some("ing".to_string()),
+        ..Default::default()
+      },
+      Delta {
+        reasoning_signature: Some("sig".to_string()),
+        ..Default::default()
+      },
+      Delta {
+        redacted_reasoning: Some("data".to_string()),
+        ..Default::default()
+      },
+      Delta {
+        reasoning_content: Some("More".to_string()),
+        ..Default::default()
+      },
+    ] {
+      response.add_chunk(chunk(delta));
+    }
+    let shown = response.add_chunk(chunk(Delta {
+      content: Some("Answer".to_string()),
+      ..Default::default()
+    }));
+
+    assert_eq!(shown.answer, "Answer");
+    assert_eq!(response.reasoning.len(), 3);
+    assert_eq!(response.reasoning[0].text, "Thinking");
+    assert_eq!(response.reasoning[0].signature.as_deref(), Some("sig"));
+    assert_eq!(response.reasoning[1].redacted.as_deref(), Some("data"));
+    assert_eq!(response.reasoning[2].text, "More");
+  }
+
+  #[test]
+  fn test_turn_response_accumulates_tool_call_variations() {
+    use super::{StreamChunk, TurnResponse};
+
+    let mut response = TurnResponse::default();
+    for data in [
+      // OpenAI: id and name first, then argument deltas by index
+      r#"{"choices":[{"delta":{"tool_calls":[{"index":0,"id":"a","function":{"name":"echo","arguments":""}}]}}]}"#,
+      r#"{"choices":[{"delta":{"tool_calls":[{"index":0,"function":{"arguments":"{\"x\":"}}]}}]}"#,
+      r#"{"choices":[{"delta":{"tool_calls":[{"index":0,"function":{"arguments":"1}"}}]}}]}"#,
+      // Index reused for another call, with arguments as an object and then sent again whole
+      r#"{"choices":[{"delta":{"tool_calls":[{"index":0,"id":"b","function":{"name":"ls","arguments":{"path":"."}}}]}}]}"#,
+      r#"{"choices":[{"delta":{"tool_calls":[{"index":0,"function":{"arguments":"{\"path\":\".\"}"}}]}}]}"#,
+      // Whole calls without index or id
+      r#"{"choices":[{"delta":{"tool_calls":[{"function":{"name":"pwd","arguments":"{}"}}]}}]}"#,
+    ] {
+      response.add_chunk(serde_json::from_str::<StreamChunk>(data).unwrap());
+    }
+
+    let ids: Vec<_> = response
+      .tool_calls
+      .iter()
+      .map(|call| call.id.as_str())
+      .collect();
+    assert_eq!(ids, ["a", "b", "call_2"]);
+    assert_eq!(response.tool_calls[1].function.name, "ls");
+    assert_eq!(
+      response.tool_arguments,
+      [r#"{"x":1}"#, r#"{"path":"."}"#, "{}"]
+    );
+  }
+
+  #[test]
+  fn test_retry_after() {
+    use reqwest::header::{HeaderMap, HeaderValue};
+    use std::time::Duration;
+
+    let mut headers = HeaderMap::new();
+    assert_eq!(super::retry_after(&headers), None);
+    headers.insert("retry-after", HeaderValue::from_static("7"));
+    assert_eq!(super::retry_after(&headers), Some(Duration::from_secs(7)));
+    headers.insert(
+      "retry-after",
+      HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+    );
+    assert_eq!(super::retry_after(&headers), Some(Duration::ZERO));
+    headers.insert("retry-after-ms", HeaderValue::from_static("1500"));
+    assert_eq!(
+      super::retry_after(&headers),
+      Some(Duration::from_millis(1500))
+    );
+    headers.insert("retry-after-ms", HeaderValue::from_static("inf"));
+    assert_eq!(super::retry_after(&headers), Some(Duration::MAX));
+  }
 }
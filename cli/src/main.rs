@@ -1,245 +1,1116 @@
+mod approval;
+mod artifacts;
+mod auth;
+mod budget;
 mod config;
+mod context;
 mod executor;
+mod exit;
 mod llm_client;
+mod output;
+mod policy;
+mod providers;
+mod redact;
+mod template;
+mod tokens;
+mod transform;
+mod usage;
 
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
 use futures::StreamExt;
+use std::collections::HashMap;
 use std::io::{self, Read};
 use std::path::PathBuf;
-use tracing::{debug, error, info};
+use std::process::ExitCode;
+use std::sync::Arc;
+use tracing::{debug, error, info, warn};
 use tracing_subscriber::EnvFilter;
 
-use crate::llm_client::{ToolCall, ToolCallFunction};
+use crate::budget::Budget;
+use crate::exit::ExitStatus;
+use crate::llm_client::{ClientChain, LlmClient, StreamEvent, ToolCall, ToolChoice, TurnResponse};
+use crate::output::{Event, OutputFormat};
+use crate::usage::{TokenUsage, UsageTracker};
+
+// How many times the model is asked to fix a final answer that doesn't match --output-schema
+const OUTPUT_SCHEMA_RETRIES: usize = 2;
+
+// How many times a turn is started over when its response stream breaks off
+const STREAM_RETRIES: usize = 2;
+
+// What the model gets for a call a dry run didn't execute, unless the tool has a dry_run_output
+const DRY_RUN_PLACEHOLDER: &str = "[Dry run: the command was not executed and has no output]";
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-  /// Path to the configuration file
-  #[arg(short, long)]
-  config: PathBuf,
+  #[command(subcommand)]
+  command: Option<Command>,
 
-  /// Model to use (defaults to environment variable LLM_CLI_MODEL or "gpt-4")
-  #[arg(short, long)]
+  /// Path to the configuration file (required)
+  #[arg(short, long, global = true)]
+  config: Option<PathBuf>,
+
+  /// Model to use (defaults to the profile's model, model.name in the config, then LLM_CLI_MODEL,
+  /// then "gpt-4")
+  #[arg(short, long, global = true)]
   model: Option<String>,
 
+  /// Provider profile from the config to use (defaults to profile in the config, then the
+  /// LLM_CLI_* variables)
+  #[arg(short, long, global = true)]
+  profile: Option<String>,
+
+  /// System prompt, overriding the one in the config file (use @path to read it from a file)
+  #[arg(short, long, global = true)]
+  system: Option<String>,
+
+  /// Prompt template to render instead of reading the prompt from stdin
+  #[arg(short = 't', long, global = true)]
+  prompt_template: Option<PathBuf>,
+
+  /// Template variable as key=value (can be repeated)
+  #[arg(long = "var", value_name = "KEY=VALUE", value_parser = template::parse_var, requires = "prompt_template", global = true)]
+  vars: Vec<(String, String)>,
+
+  /// Whether the model must call tools: auto, none, required or a tool name
+  #[arg(long)]
+  tool_choice: Option<ToolChoice>,
+
+  /// JSON schema the final answer must match; only the validated JSON is printed
+  #[arg(long)]
+  output_schema: Option<PathBuf>,
+
+  /// Format of what is written to stdout
+  #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+  output_format: OutputFormat,
+
+  /// Only print the final answer
+  #[arg(short, long)]
+  quiet: bool,
+
+  /// Show the model's reasoning on stderr, where the provider sends it
+  #[arg(long)]
+  show_reasoning: bool,
+
+  /// Validate tool calls and print what they would run, without running them
+  #[arg(long)]
+  dry_run: bool,
+
   /// Log file path for conversation history
   #[arg(short, long, default_value = None)]
   log_file: Option<PathBuf>,
+
+  #[command(flatten)]
+  params: llm_client::ModelParams,
+
+  #[command(flatten)]
+  limits: budget::Limits,
+
+  #[command(flatten)]
+  context: context::ContextConfig,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+  /// Count the tokens of the prompt and the tool definitions, without sending anything
+  Count {
+    /// Context window to measure against (defaults to the model's, where it's known)
+    #[arg(long)]
+    context_size: Option<u64>,
+  },
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-  // Initialize tracing
+fn main() -> ExitCode {
+  let args = Args::parse();
+
+  // Initialize tracing, keeping stdout for the answer
+  let default_level = if args.quiet { "warn" } else { "info" };
   tracing_subscriber::fmt()
-    .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+    .with_writer(io::stderr)
+    .with_env_filter(
+      EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(default_level)),
+    )
     .init();
 
-  let args = Args::parse();
+  let runtime = tokio::runtime::Runtime::new().expect("Failed building the Runtime");
+  let status = runtime.block_on(async {
+    tokio::select! {
+      result = run(args) => result.unwrap_or_else(|e| {
+        eprintln!("Error: {:?}", e);
+        ExitStatus::of(&e)
+      }),
+      Ok(()) = interrupted() => {
+        error!("Interrupted");
+        ExitStatus::Interrupted
+      }
+    }
+  });
+  // Blocking work abandoned on a timeout or Ctrl-C, such as opening an approval pipe nobody reads,
+  // mustn't keep us from exiting
+  runtime.shutdown_background();
+  status.into()
+}
 
+// Resolves on Ctrl-C, or on SIGTERM as sent by CI runners cancelling a job
+async fn interrupted() -> Result<()> {
+  #[cfg(unix)]
+  {
+    use tokio::signal::unix::{SignalKind, signal};
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+      result = tokio::signal::ctrl_c() => result?,
+      _ = terminate.recv() => {}
+    }
+  }
+  #[cfg(not(unix))]
+  tokio::signal::ctrl_c().await?;
+  Ok(())
+}
+
+async fn run(args: Args) -> Result<ExitStatus> {
   // Load configuration
-  let config = config::Config::from_file(&args.config)?;
+  let Some(config_path) = &args.config else {
+    return Err(anyhow::anyhow!("--config is required").context(ExitStatus::Config));
+  };
+  let config = config::Config::from_file(config_path).context(ExitStatus::Config)?;
   info!("Loaded {} tools from config", config.tools.len());
 
-  // Initialize LLM client
-  let llm_client = llm_client::LlmClient::from_env()?;
+  let profile = match args.profile.as_deref().or(config.profile.as_deref()) {
+    Some(name) => Some((name, config.provider(name).context(ExitStatus::Config)?)),
+    None => None,
+  };
+  let model = args
+    .model
+    .clone()
+    .or_else(|| profile.and_then(|(_, profile)| profile.model.clone()))
+    .or_else(|| config.model.name.clone())
+    .or_else(|| std::env::var("LLM_CLI_MODEL").ok())
+    .unwrap_or_else(|| "gpt-4".to_string());
+
+  if let Some(Command::Count { context_size }) = args.command {
+    return count(&args, &config, &model, context_size);
+  }
 
-  // Initialize executor
-  let executor = executor::Executor::new();
+  let token_vars = token_vars(&config);
 
-  // Read prompt from stdin
-  let mut prompt = String::new();
-  io::stdin().read_to_string(&mut prompt)?;
+  // Initialize LLM clients, for the selected profile and its fallbacks
+  let primary = match profile {
+    Some((name, profile)) => LlmClient::from_profile(name, profile, &config.shell, &token_vars)
+      .map(|client| {
+        client.with_tool_protocol(profile.tool_protocol.unwrap_or(config.tool_protocol))
+      }),
+    None => LlmClient::from_env(&config.shell, &token_vars)
+      .map(|client| client.with_tool_protocol(config.tool_protocol)),
+  }
+  .context(ExitStatus::Config)?;
+  let fallbacks = config
+    .fallback
+    .iter()
+    .map(|name| {
+      let profile = config.provider(name)?;
+      Ok(
+        LlmClient::from_profile(name, profile, &config.shell, &token_vars)?
+          .with_model(profile.model.clone())
+          .with_tool_protocol(profile.tool_protocol.unwrap_or(config.tool_protocol)),
+      )
+    })
+    .collect::<Result<Vec<_>>>()
+    .context(ExitStatus::Config)?;
+  let llm_client = ClientChain::new(primary, fallbacks);
 
-  if prompt.trim().is_empty() {
-    error!("No prompt provided");
-    return Ok(());
+  // Initialize executor, keeping the API tokens from the tools
+  // Secrets are redacted from tool output before it's saved anywhere or sent anywhere
+  let redactor =
+    Arc::new(redact::Redactor::new(&config.redaction, &token_vars).context(ExitStatus::Config)?);
+  let mut executor = executor::Executor::new()
+    .with_hidden_env(token_vars.clone())
+    .with_redactor(redactor.clone());
+  if let Some(dir) = &config.artifact_dir {
+    executor = executor.with_artifact_dir(dir);
   }
 
   // Initialize conversation log
-  let mut conversation_log = ConversationLog::new(&args.log_file);
+  let mut conversation_log = ConversationLog::new(&args.log_file, redactor);
 
-  // Create initial message
-  let mut messages = vec![llm_client::Message::User {
-    content: prompt.trim().to_string(),
-  }];
+  let mut messages = initial_messages(&args, &config)?;
+  for message in &messages {
+    conversation_log.add_message(message).await?;
+  }
 
-  conversation_log.add_message(&messages[0]).await?;
+  let tool_definitions = tool_definitions(&config);
 
-  // Convert tools to LLM format
-  let tool_definitions: Vec<_> = config
-    .tools
+  let mut tool_choice = args.tool_choice.clone().or(config.tool_choice.clone());
+  config
+    .validate_tool_choice(tool_choice.as_ref())
+    .context(ExitStatus::Config)?;
+
+  let params = config.model.params.clone().merge(args.params.clone());
+
+  let output_schema = args
+    .output_schema
+    .as_deref()
+    .map(output::OutputSchema::from_file)
+    .transpose()
+    .context(ExitStatus::Config)?;
+  if output_schema.is_some() && config.final_tool.is_some() {
+    return Err(
+      anyhow::anyhow!("--output-schema can't be combined with a final_tool")
+        .context(ExitStatus::Config),
+    );
+  }
+  let structured = output_schema.is_some() || config.final_tool.is_some();
+  let mut output = output::Output::new(
+    args.output_format,
+    args.quiet,
+    structured,
+    args.show_reasoning,
+  );
+  let mut schema_retries = 0;
+  let mut usage_tracker = UsageTracker::new(&model, &config.pricing);
+
+  let limits = config.limits.clone().merge(args.limits.clone());
+  if limits.max_cost.is_some() && usage_tracker.total_cost().is_none() {
+    warn!("max_cost is ignored, there is no pricing for {}", model);
+  }
+  let mut budget = Budget::new(limits);
+  let mut policy = policy::Policy::new(&config.policy).context(ExitStatus::Config)?;
+  let approver =
+    approval::Approver::new(&config.approval, &config.shell).with_hidden_env(token_vars.clone());
+  let context_config = config.context.clone().merge(args.context.clone());
+  let tokenizer = tokens::Tokenizer::for_model(&model);
+  let tool_tokens = tokenizer.count_tools(&tool_definitions);
+  let request_tokens =
+    |messages: &[llm_client::Message]| tokenizer.count_messages(messages) + tool_tokens;
+  let mut exhausted: Option<String> = None;
+  let mut wrapping_up = false;
+  let mut status = ExitStatus::Success;
+  let mut invalid_tool_calls = 0;
+  let tool_names = tool_definitions
     .iter()
-    .map(|tool| tool.to_llm_definition())
-    .collect();
-
-  // Main conversation loop
-  loop {
-    // Create request
-    let request = llm_client::LlmRequest {
-      messages: messages.clone(),
-      stream: true,
-      tools: tool_definitions.clone(),
-      model: args
-        .model
-        .clone()
-        .or_else(|| std::env::var("LLM_CLI_MODEL").ok())
-        .unwrap_or_else(|| "gpt-4".to_string()),
-    };
+    .map(|definition| definition.function.name.as_str())
+    .collect::<Vec<_>>()
+    .join(", ");
 
-    // Stream response
-    let mut stream = llm_client.stream_completion(request).await?;
-    let mut accumulated_text = Some(String::new());
-    let mut tool_calls: Vec<ToolCall> = Vec::new();
-
-    let mut tool_arguments_jsons: Vec<String> = Vec::new();
-
-    while let Some(event) = stream.next().await {
-      match event? {
-        llm_client::StreamEvent::Chunk(chunk) => {
-          debug!("Received chunk: {:?}", &chunk);
-
-          for choice in chunk.choices {
-            if let Some(delta) = choice.delta {
-              if let Some(content) = delta.content {
-                print!("{}", &content);
-                // Initialize accumulated_text if it's None
-                if accumulated_text.is_none() {
-                  accumulated_text = Some(String::new());
-                }
-                // Append content to accumulated_text
-                if let Some(ref mut text) = accumulated_text {
-                  text.push_str(&content);
-                }
-              }
-              // Handle tool calls
-              if let Some(calls) = delta.tool_calls {
-                calls.into_iter().for_each(|call| {
-                  debug!("Received tool call: {:?}", &call);
-                  if let Some(id) = call.id {
-                    debug!("Tool call function: {:?}", call.function.name);
-                    tool_calls.push(ToolCall {
-                      id: id,
-                      tool_type: "function".to_string(),
-                      function: ToolCallFunction {
-                        name: call
-                          .function
-                          .name
-                          .expect("Tool call with id must have a name"),
-                        arguments: serde_json::Value::Null,
-                      },
-                    });
-                    tool_arguments_jsons.push(call.function.arguments);
-                  } else {
-                    tool_arguments_jsons[call.index].push_str(&call.function.arguments);
-                  }
-                });
+  // Main conversation loop, in a block so that usage is reported however it ends
+  let mut turn = 0;
+  let result = async {
+    loop {
+      turn += 1;
+
+      // Once a limit is hit, the model gets one last turn to wrap up
+      if exhausted.is_none() {
+        exhausted = budget
+          .exhausted(turn - 1, &usage_tracker)
+          .or_else(|| budget.request_exceeds(request_tokens(&messages), &usage_tracker));
+      }
+      if let Some(reason) = &exhausted
+        && !wrapping_up
+      {
+        wrapping_up = true;
+        output.status(&format!("--- Budget exhausted: {} ---", reason));
+        let instruction = match &config.final_tool {
+          Some(final_tool) => format!("call the {} tool", final_tool.name),
+          None => "don't call any more tools, give your final answer".to_string(),
+        };
+        let wrap_up = llm_client::Message::User {
+          content: format!(
+            "The budget for this task is exhausted ({}). Wrap up now: {} based on what you have so \
+             far.",
+            reason, instruction
+          ),
+        };
+        conversation_log.add_message(&wrap_up).await?;
+        messages.push(wrap_up);
+      }
+
+      // Keep the conversation within the context budget: elide old tool outputs first, then
+      // summarise the turns before the most recent ones
+      if let Some(max_tokens) = context_config.max_tokens
+        && request_tokens(&messages) > max_tokens
+      {
+        let before = request_tokens(&messages);
+        let recent = context_config.recent_start(&messages);
+        let elided = context::elide_tool_outputs(
+          tokenizer,
+          &mut messages,
+          recent,
+          max_tokens.saturating_sub(tool_tokens),
+        );
+        if elided > 0 {
+          let after = request_tokens(&messages);
+          info!(
+            "Elided {} old tool outputs, from about {} to {} tokens",
+            elided, before, after
+          );
+          conversation_log
+            .add_compaction(turn, "elide", elided, before, after, None)
+            .await?;
+        }
+
+        let start = context::summary_start(&messages);
+        let before = request_tokens(&messages);
+        if before > max_tokens && start < recent {
+          let summary = budget
+            .within_time(async {
+              Ok(context::summarize(&llm_client, &model, &messages[start..recent]).await)
+            })
+            .await?;
+          match summary {
+            Ok((response, served_by)) => {
+              if let Some(usage) = &response.usage {
+                let usage = TokenUsage::from(usage);
+                let cost = usage_tracker.record(&served_by, usage);
+                conversation_log.add_usage(turn, &usage, cost).await?;
               }
+              messages.splice(start..recent, [context::summary_message(&response.text)]);
+              let after = request_tokens(&messages);
+              info!(
+                "Summarised {} earlier messages, from about {} to {} tokens",
+                recent - start,
+                before,
+                after
+              );
+              conversation_log
+                .add_compaction(
+                  turn,
+                  "summarize",
+                  recent - start,
+                  before,
+                  after,
+                  Some(&response.text),
+                )
+                .await?;
+            }
+            Err(e) => {
+              let diagnostic = format!("Failed to summarise the conversation: {}", e);
+              warn!("{}", diagnostic);
+              conversation_log.add_diagnostic(turn, &diagnostic).await?;
             }
           }
         }
-        llm_client::StreamEvent::Done => {
-          debug!("Stream completed");
-          break;
+
+        if request_tokens(&messages) > max_tokens {
+          warn!(
+            "The conversation is still over the context budget of {} tokens, the most recent turns \
+             are kept whole",
+            max_tokens
+          );
         }
       }
-    }
 
-    // Deserialize accumulated arguments and update tool calls
-    for (i, args_string) in tool_arguments_jsons.into_iter().enumerate() {
-      if let Ok(args) = serde_json::from_str::<serde_json::Value>(&args_string) {
-        if let Some(tool_call) = tool_calls.get_mut(i) {
-          tool_call.function.arguments = args;
-        }
+      // Once the answer failed the output schema, or the run is wrapping up, the remaining turns
+      // only produce the answer. The schema also goes with any turn in which the model can't call
+      // tools, so it gets the answer right the first time.
+      let final_turn = schema_retries > 0 || wrapping_up;
+      let answer_only =
+        final_turn || tool_definitions.is_empty() || tool_choice == Some(ToolChoice::None);
+
+      // Create request
+      let request = llm_client::LlmRequest {
+        messages: messages.clone(),
+        stream: true,
+        tools: tool_definitions.clone(),
+        model: model.clone(),
+        params: params.clone(),
+        tool_choice: if final_turn {
+          Some(match &config.final_tool {
+            Some(final_tool) => ToolChoice::Tool(final_tool.name.clone()),
+            None => ToolChoice::None,
+          })
+        } else {
+          tool_choice.clone()
+        },
+        output_schema: output_schema
+          .as_ref()
+          .filter(|_| answer_only)
+          .map(|schema| schema.schema.clone()),
+      };
+
+      // Stream the response, within the time that is left, starting the turn over if the stream
+      // breaks off
+      let (response, served_by) = budget
+        .within_time(async {
+          let mut stream_retries = 0;
+          loop {
+            let completion = llm_client
+              .stream_completion(&request)
+              .await
+              .context(ExitStatus::Provider)?;
+            match read_response(completion.stream, &mut output).await {
+              Ok(response) => break Ok((response, completion.model)),
+              Err(e) if stream_retries < STREAM_RETRIES && output.can_retry_turn() => {
+                stream_retries += 1;
+                let diagnostic = format!(
+                  "The response stream broke off, retrying the turn ({}/{}): {}",
+                  stream_retries, STREAM_RETRIES, e
+                );
+                warn!("{}", diagnostic);
+                conversation_log.add_diagnostic(turn, &diagnostic).await?;
+                output.emit(Event::TurnRetry {
+                  turn,
+                  reason: &e.to_string(),
+                })?;
+              }
+              Err(e) => break Err(e.context(ExitStatus::Provider)),
+            }
+          }
+        })
+        .await?;
+
+      if let Some(usage) = &response.usage {
+        let usage = TokenUsage::from(usage);
+        let cost = usage_tracker.record(&served_by, usage);
+        output.emit(Event::Usage { turn, usage, cost })?;
+        conversation_log.add_usage(turn, &usage, cost).await?;
       } else {
-        error!("Failed to parse tool arguments: {}", args_string);
+        debug!("No usage reported for turn {}", turn);
       }
-    }
 
-    // If we got text, add it as assistant message
-    if !accumulated_text.is_none() {
-      println!(); // New line after streaming
-    } else {
-      tracing::debug!("No text response received.");
-    }
+      let TurnResponse {
+        text,
+        refusal,
+        reasoning,
+        mut tool_calls,
+        tool_arguments,
+        finish_reason,
+        ..
+      } = response;
 
-    let assistant_msg = llm_client::Message::Assistant {
-      content: accumulated_text,
-      tool_calls: if tool_calls.len() > 0 {
-        Some(tool_calls.clone())
-      } else {
-        None
-      },
-    };
-    conversation_log.add_message(&assistant_msg).await?;
-    messages.push(assistant_msg);
-
-    // Execute tool calls
-    println!("\n--- Executing tools ---");
-    for tool_call in &tool_calls {
-      println!("Tool: {} ({})", tool_call.function.name, tool_call.id);
-      println!("Arguments: {:?}", tool_call.function.arguments);
-
-      // Find the tool in config
-      let tool = config
-        .tools
-        .iter()
-        .find(|t| t.name == tool_call.function.name)
-        .ok_or_else(|| anyhow::anyhow!("Tool not found: {}", tool_call.function.name))?;
+      let diagnostic = match finish_reason.as_deref() {
+        Some("length") => Some("The response was cut off at the token limit".to_string()),
+        Some("content_filter") => {
+          Some("The response was stopped by the provider's content filter".to_string())
+        }
+        _ => refusal
+          .as_ref()
+          .map(|refusal| format!("The model refused: {}", refusal)),
+      };
+      if let Some(diagnostic) = diagnostic {
+        warn!("{}", diagnostic);
+        conversation_log.add_diagnostic(turn, &diagnostic).await?;
+      }
 
-      // Execute the tool
-      match executor
-        .execute_tool(tool, &tool_call.function.arguments, &config.shell)
-        .await
-      {
-        Ok(output) => {
-          println!("Output:\n{}", output);
+      // Deserialize accumulated arguments and update tool calls, keeping parse errors for the model
+      let mut argument_errors = vec![None; tool_calls.len()];
+      for (i, args_string) in tool_arguments.into_iter().enumerate() {
+        // Calls without parameters may come without arguments at all
+        let args_string = if args_string.trim().is_empty() {
+          "{}".to_string()
+        } else {
+          args_string
+        };
+        match serde_json::from_str::<serde_json::Value>(&args_string) {
+          Ok(args) => {
+            if let Some(tool_call) = tool_calls.get_mut(i) {
+              tool_call.function.arguments = args;
+            }
+          }
+          Err(e) => {
+            error!("Failed to parse tool arguments: {}", args_string);
+            if let Some(argument_error) = argument_errors.get_mut(i) {
+              *argument_error = Some(e.to_string());
+            }
+          }
+        }
+      }
 
-          // Log first while we still own output
-          conversation_log
-            .add_tool_result(&tool_call, &output)
-            .await?;
+      // A refusal stands in for the answer
+      let answer = match refusal {
+        Some(refusal) if text.is_empty() => refusal,
+        _ => text,
+      };
+      let assistant_msg = llm_client::Message::Assistant {
+        content: Some(answer.clone()),
+        reasoning,
+        tool_calls: if !tool_calls.is_empty() {
+          Some(tool_calls.clone())
+        } else {
+          None
+        },
+      };
+      conversation_log.add_message(&assistant_msg).await?;
+      messages.push(assistant_msg);
+
+      // Execute tool calls
+      let mut final_result = None;
+      if !tool_calls.is_empty() {
+        output.status("\n--- Executing tools ---");
+      }
+      for (tool_call, argument_error) in tool_calls.iter().zip(argument_errors) {
+        output.emit(Event::ToolCallStarted {
+          id: &tool_call.id,
+          name: &tool_call.function.name,
+          arguments: &tool_call.function.arguments,
+        })?;
 
-          // Convert to message format (simplified for MVP)
-          let tool_msg = llm_client::Message::Tool {
-            tool_call_id: tool_call.id.clone(),
-            content: output,
+        // Invalid calls are the model's mistakes, so they're reported back to it for as long as it
+        // keeps recovering from them. Calls the policy denies are treated the same way.
+        let name = &tool_call.function.name;
+        let tool = config.tools.iter().find(|t| &t.name == name);
+        let final_tool = config.final_tool.as_ref().filter(|t| &t.name == name);
+        let builtin = config.spills_output()
+          && [artifacts::READ_OUTPUT, artifacts::GREP_OUTPUT].contains(&name.as_str());
+        let validation = match (argument_error, tool, final_tool) {
+          (Some(e), _, _) => Err(format!(
+            "The arguments are not valid JSON ({}). Call {} again with a JSON object.",
+            e, name
+          )),
+          (None, Some(tool), _) => tool
+            .validate_input(&tool_call.function.arguments)
+            .map_err(|e| format!("Invalid arguments: {}", e)),
+          (None, None, Some(final_tool)) => final_tool
+            .validate_input(&tool_call.function.arguments)
+            .map_err(|e| format!("Invalid arguments: {}", e)),
+          // Builtins check their arguments as they run
+          (None, None, None) if builtin => Ok(()),
+          (None, None, None) => Err(format!(
+            "There is no tool named {}. The available tools are: {}.",
+            name, tool_names
+          )),
+        };
+        let decision = match (&validation, final_tool) {
+          (Ok(()), None) => policy.evaluate(name, &tool_call.function.arguments),
+          _ => policy::Decision::Allow,
+        };
+        let validation = validation.and_then(|()| match &decision {
+          policy::Decision::Deny(reason) => Err(format!("Denied by the policy: {}", reason)),
+          _ => Ok(()),
+        });
+        if let Err(e) = validation {
+          invalid_tool_calls += 1;
+          let status = match decision {
+            policy::Decision::Deny(_) => ExitStatus::PolicyViolation,
+            _ => ExitStatus::InvalidToolCalls,
           };
+          give_up_after(invalid_tool_calls, config.tool_error_retries, &e, status)?;
+          warn!("Rejected call of tool {}: {}", name, e);
+          let error_msg = format!("Error: {}", e);
+          respond_to_tool_call(
+            &mut output,
+            &mut conversation_log,
+            &mut messages,
+            tool_call,
+            error_msg,
+            false,
+            None,
+          )
+          .await?;
+          continue;
+        }
 
-          messages.push(tool_msg);
+        // The final tool ends the conversation once its arguments are valid
+        if final_tool.is_some() {
+          final_result = Some(tool_call.function.arguments.clone());
+          break;
         }
-        Err(e) => {
-          error!("Tool execution failed: {}", e);
-          let error_msg = format!("Error: {}", e);
 
-          conversation_log
-            .add_tool_result(&tool_call, &error_msg)
-            .await?;
+        // Every call needs a result, so calls past a limit are answered without running them
+        if exhausted.is_none() {
+          exhausted = budget
+            .record_tool_call(name, tool.and_then(|tool| tool.max_calls))
+            .or_else(|| budget.exhausted(turn - 1, &usage_tracker));
+        }
+        if let Some(reason) = &exhausted {
+          let skipped_msg = format!("Not executed, the budget is exhausted: {}", reason);
+          respond_to_tool_call(
+            &mut output,
+            &mut conversation_log,
+            &mut messages,
+            tool_call,
+            skipped_msg,
+            false,
+            None,
+          )
+          .await?;
+          continue;
+        }
 
-          let tool_msg = llm_client::Message::Tool {
-            tool_call_id: tool_call.id.clone(),
-            content: error_msg.clone(),
+        // Calls that need approval wait for it, and a denial goes back to the model with the
+        // reviewer's reason. A dry run only shows that they would.
+        let approval_reason = match &decision {
+          policy::Decision::RequireApproval(reason) => Some(format!("required by {}", reason)),
+          _ if tool.is_some_and(|tool| tool.approval == approval::Approval::Required) => {
+            Some(format!("{} requires approval", name))
+          }
+          _ => None,
+        };
+        if let Some(reason) = approval_reason.as_ref().filter(|_| !args.dry_run) {
+          let request = approval::ApprovalRequest {
+            id: &tool_call.id,
+            tool: name,
+            arguments: &tool_call.function.arguments,
+            command: tool.map(|tool| tool.command.as_str()),
+            reason,
           };
+          let review = approver
+            .review(&request)
+            .await
+            .unwrap_or_else(|e| approval::Review::Denied(format!("the approver failed: {}", e)));
+          conversation_log.add_review(tool_call, &review).await?;
+          if let approval::Review::Denied(reason) = review {
+            invalid_tool_calls += 1;
+            let e = format!("Denied by the reviewer: {}", reason);
+            give_up_after(
+              invalid_tool_calls,
+              config.tool_error_retries,
+              &e,
+              ExitStatus::PolicyViolation,
+            )?;
+            warn!("Call of tool {} denied: {}", name, reason);
+            respond_to_tool_call(
+              &mut output,
+              &mut conversation_log,
+              &mut messages,
+              tool_call,
+              e,
+              false,
+              None,
+            )
+            .await?;
+            continue;
+          }
+          info!("Call of tool {} approved", name);
+        }
+
+        invalid_tool_calls = 0;
+
+        // Execute the tool, within the time that is left. A dry run runs the tool's dry_run_output
+        // script instead, if it has one.
+        policy.record_call(name);
+        let mut invocation = None;
+        if let Some(tool) = tool.filter(|_| args.dry_run) {
+          match executor.plan(tool, &tool_call.function.arguments, &config.shell) {
+            Ok(plan) => {
+              output.emit(Event::DryRun {
+                id: &tool_call.id,
+                name,
+                invocation: &plan,
+                approval: approval_reason.as_deref(),
+              })?;
+              conversation_log
+                .add_dry_run(tool_call, &plan, approval_reason.as_deref())
+                .await?;
+              invocation = Some(plan);
+            }
+            Err(e) => {
+              error!("Tool {} can't run: {}", name, e);
+              let error_msg = format!("Error: {}", e);
+              respond_to_tool_call(
+                &mut output,
+                &mut conversation_log,
+                &mut messages,
+                tool_call,
+                error_msg,
+                false,
+                None,
+              )
+              .await?;
+              continue;
+            }
+          }
+        }
+        let dry_run_tool = tool.filter(|_| invocation.is_some()).and_then(|tool| {
+          let script = tool.dry_run_output.clone()?;
+          Some(config::Tool {
+            command: script,
+            ..tool.clone()
+          })
+        });
+        let execution = async {
+          match tool {
+            Some(_) if invocation.is_some() && dry_run_tool.is_none() => {
+              Ok((DRY_RUN_PLACEHOLDER.to_string(), true, None))
+            }
+            Some(tool) => {
+              let tool = dry_run_tool.as_ref().unwrap_or(tool);
+              let mut outcome = executor
+                .execute_tool(tool, &tool_call.function.arguments, &config.shell)
+                .await?;
+              let content = executor.render(tool, &mut outcome, &config.shell)?;
+              Ok((content, outcome.succeeded(tool), Some(outcome)))
+            }
+            None => executor
+              .execute_builtin(name, &tool_call.function.arguments)
+              .map(|content| (content, true, None)),
+          }
+        };
+        let result = match budget.remaining_time() {
+          Some(remaining) => tokio::time::timeout(remaining, execution)
+            .await
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("Timed out, the run is out of time"))),
+          None => execution.await,
+        };
+        let (content, success, outcome) = match result {
+          Ok((content, success, outcome)) => {
+            if let Some(outcome) = outcome.as_ref().filter(|_| !success) {
+              error!("Tool {} failed with {}", name, outcome.status());
+            }
+            (content, success, outcome)
+          }
+          Err(e) => {
+            error!("Tool execution failed: {}", e);
+            (format!("Error: {}", e), false, None)
+          }
+        };
+        respond_to_tool_call(
+          &mut output,
+          &mut conversation_log,
+          &mut messages,
+          tool_call,
+          content,
+          success,
+          outcome.as_ref(),
+        )
+        .await?;
+      }
 
-          messages.push(tool_msg);
+      if !tool_calls.is_empty() {
+        output.status("--- End tool execution ---\n");
+      }
+
+      // Log tool calls
+      for tool_call in &tool_calls {
+        conversation_log.add_tool_call(tool_call).await?;
+      }
+
+      output.emit(Event::TurnEnd {
+        turn,
+        tool_calls: tool_calls.len(),
+        finish_reason: finish_reason.as_deref(),
+      })?;
+
+      if let Some(result) = final_result {
+        output.emit(Event::Final {
+          text: None,
+          output: Some(&result),
+        })?;
+        break;
+      }
+
+      // The wrap-up turn gets no second chances
+      if wrapping_up {
+        if let Some(schema) = &output_schema {
+          match schema.validate(&answer) {
+            Ok(result) => output.emit(Event::Final {
+              text: None,
+              output: Some(&result),
+            })?,
+            Err(errors) => error!(
+              "Final answer doesn't match the output schema: {}",
+              errors.join("; ")
+            ),
+          }
+        } else if let Some(final_tool) = &config.final_tool {
+          error!("Model finished without calling {}", final_tool.name);
+        } else {
+          output.emit(Event::Final {
+            text: Some(&answer),
+            output: None,
+          })?;
+        }
+        break;
+      }
+
+      if !tool_calls.is_empty() {
+        tool_choice = config.tool_choice_after_calls(tool_choice);
+        continue;
+      }
+
+      output.status("--- No tool calls made ---");
+
+      if let Some(schema) = &output_schema {
+        match schema.validate(&answer) {
+          Ok(result) => {
+            output.emit(Event::Final {
+              text: None,
+              output: Some(&result),
+            })?;
+            break;
+          }
+          Err(errors) if schema_retries < OUTPUT_SCHEMA_RETRIES => {
+            schema_retries += 1;
+            error!(
+              "Final answer doesn't match the output schema: {}",
+              errors.join("; ")
+            );
+            let retry = llm_client::Message::User {
+              content: format!(
+                "Your final answer must be a single JSON document matching this JSON schema:\n\n\
+                 {}\n\nIt failed validation:\n- {}\n\nReply with the corrected JSON only.",
+                serde_json::to_string_pretty(&schema.schema)?,
+                errors.join("\n- ")
+              ),
+            };
+            conversation_log.add_message(&retry).await?;
+            messages.push(retry);
+            continue;
+          }
+          Err(errors) => {
+            return Err(
+              anyhow::anyhow!(
+                "Final answer doesn't match the output schema: {}",
+                errors.join("; ")
+              )
+              .context(ExitStatus::ValidationFailed),
+            );
+          }
+        }
+      }
+
+      match &config.final_tool {
+        // With a final tool the model has to finish by calling it, so insist once
+        Some(final_tool) if tool_choice != Some(ToolChoice::Tool(final_tool.name.clone())) => {
+          let reminder = llm_client::Message::User {
+            content: format!("Call the {} tool to finish.", final_tool.name),
+          };
+          conversation_log.add_message(&reminder).await?;
+          messages.push(reminder);
+          tool_choice = Some(ToolChoice::Tool(final_tool.name.clone()));
+        }
+        Some(final_tool) => {
+          error!("Model finished without calling {}", final_tool.name);
+          status = ExitStatus::ValidationFailed;
+          break;
+        }
+        // If no tool calls were made, we can exit the loop
+        None => {
+          output.emit(Event::Final {
+            text: Some(&answer),
+            output: None,
+          })?;
+          break;
         }
       }
     }
+    Ok::<_, anyhow::Error>(())
+  }
+  .await;
 
-    println!("--- End tool execution ---\n");
+  if usage_tracker.turns > 0 {
+    output.status(&usage_tracker.summary());
+  }
+  let logged = conversation_log.add_usage_summary(&usage_tracker).await;
+  result?;
+  logged?;
+
+  if let Some(reason) = exhausted {
+    error!("Stopped early, the budget is exhausted: {}", reason);
+    return Ok(ExitStatus::BudgetExhausted);
+  }
 
-    // Log tool calls
-    for tool_call in &tool_calls {
-      conversation_log.add_tool_call(tool_call).await?;
+  Ok(status)
+}
+
+// The variables holding API tokens, along with the extra headers which often carry credentials
+// too. They're kept from every command we run.
+fn token_vars(config: &config::Config) -> Vec<String> {
+  std::iter::once("LLM_CLI_TOKEN".to_string())
+    .chain(
+      config
+        .providers
+        .values()
+        .filter_map(|profile| profile.token_env.clone()),
+    )
+    .chain(
+      std::env::vars().filter_map(|(key, _)| key.starts_with("LLM_CLI_HEADER_").then_some(key)),
+    )
+    .collect()
+}
+
+// The system prompt and the task: the rendered prompt template, or the prompt from stdin
+fn initial_messages(args: &Args, config: &config::Config) -> Result<Vec<llm_client::Message>> {
+  let prompt = match &args.prompt_template {
+    Some(path) => {
+      let source = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))
+        .context(ExitStatus::Config)?;
+      let vars: HashMap<String, String> = args.vars.iter().cloned().collect();
+      let token_vars = token_vars(config);
+      template::Template::new(&vars, &config.shell)
+        .with_hidden_env(&token_vars)
+        .render(&source)
+        .context(ExitStatus::Config)?
     }
+    None => {
+      let mut prompt = String::new();
+      io::stdin().read_to_string(&mut prompt)?;
+      prompt
+    }
+  };
+
+  if prompt.trim().is_empty() {
+    return Err(anyhow::anyhow!("No prompt provided").context(ExitStatus::Config));
+  }
 
-    if tool_calls.is_empty() {
-      println!("--- No tool calls made ---");
-      // If no tool calls were made, we can exit the loop
-      break;
+  let system_prompt = match &args.system {
+    Some(system) => Some(config::PromptSource::from_arg(system)),
+    None => config.system_prompt.clone(),
+  };
+
+  let mut messages = Vec::new();
+  if let Some(system_prompt) = system_prompt {
+    messages.push(llm_client::Message::System {
+      content: system_prompt.load().context(ExitStatus::Config)?,
+    });
+  }
+  messages.push(llm_client::Message::User {
+    content: prompt.trim().to_string(),
+  });
+  Ok(messages)
+}
+
+fn tool_definitions(config: &config::Config) -> Vec<llm_client::ToolDefinition> {
+  config
+    .tools
+    .iter()
+    .map(|tool| tool.to_llm_definition())
+    .chain(
+      config
+        .final_tool
+        .iter()
+        .map(|tool| tool.to_llm_definition()),
+    )
+    .chain(
+      config
+        .spills_output()
+        .then(artifacts::tool_definitions)
+        .into_iter()
+        .flatten(),
+    )
+    .collect()
+}
+
+// `llmcli count`: what the first request would cost in tokens, part by part
+fn count(
+  args: &Args,
+  config: &config::Config,
+  model: &str,
+  context_size: Option<u64>,
+) -> Result<ExitStatus> {
+  let tokenizer = tokens::Tokenizer::for_model(model);
+  let mut rows = Vec::new();
+  for message in initial_messages(args, config)? {
+    let name = match message {
+      llm_client::Message::System { .. } => "system prompt",
+      _ => "prompt",
+    };
+    rows.push((name.to_string(), tokenizer.count_message(&message)));
+  }
+  for definition in tool_definitions(config) {
+    rows.push((
+      format!("tool {}", definition.function.name),
+      tokenizer.count_tool(&definition),
+    ));
+  }
+  let total = rows.iter().map(|(_, tokens)| tokens).sum::<u64>() + tokens::REPLY_OVERHEAD;
+
+  println!("{} tokens, counted with {}", model, tokenizer.name());
+  let width = rows.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+  for (name, tokens) in &rows {
+    println!("  {:<width$}  {:>8}", name, tokens);
+  }
+  println!("  {:<width$}  {:>8}", "total", total);
+
+  match context_size.or_else(|| tokens::context_window(model)) {
+    Some(limit) => {
+      println!(
+        "{:.1}% of the {} token context window",
+        total as f64 * 100.0 / limit as f64,
+        limit
+      );
+      if total > limit {
+        error!("The request is {} tokens over the limit", total - limit);
+        return Ok(ExitStatus::Failure);
+      }
     }
+    None => info!(
+      "The context window of {} is unknown, set --context-size",
+      model
+    ),
   }
+  Ok(ExitStatus::Success)
+}
 
+// Reads a turn's response, showing its text as it arrives
+async fn read_response(
+  mut stream: llm_client::EventStream,
+  output: &mut output::Output,
+) -> Result<TurnResponse> {
+  let mut response = TurnResponse::default();
+  while let Some(event) = stream.next().await {
+    match event? {
+      StreamEvent::Chunk(chunk) => {
+        debug!("Received chunk: {:?}", &chunk);
+        let text = response.add_chunk(chunk);
+        if !text.reasoning.is_empty() {
+          output.emit(Event::ReasoningDelta {
+            text: &text.reasoning,
+          })?;
+        }
+        if !text.answer.is_empty() {
+          output.emit(Event::TextDelta { text: &text.answer })?;
+        }
+      }
+      StreamEvent::Done => {
+        debug!("Stream completed");
+        return Ok(response);
+      }
+    }
+  }
+
+  // Some servers close the stream without a done event, but only after a finish reason
+  if response.finish_reason.is_some() {
+    return Ok(response);
+  }
+  anyhow::bail!("The stream ended before the response was complete")
+}
+
+// Ends the run once the model made more invalid or denied tool calls in a row than it's allowed,
+// classified by the last one
+fn give_up_after(rejected: usize, allowed: usize, last: &str, status: ExitStatus) -> Result<()> {
+  if rejected > allowed {
+    return Err(
+      anyhow::anyhow!(
+        "Giving up after {} invalid or denied tool calls in a row, the last one: {}",
+        rejected,
+        last
+      )
+      .context(status),
+    );
+  }
+  Ok(())
+}
+
+// Reports the result of a tool call and hands it to the model
+async fn respond_to_tool_call(
+  output: &mut output::Output,
+  conversation_log: &mut ConversationLog,
+  messages: &mut Vec<llm_client::Message>,
+  tool_call: &ToolCall,
+  content: String,
+  success: bool,
+  outcome: Option<&executor::ToolOutcome>,
+) -> Result<()> {
+  // Secrets in tool output are redacted before the model, stdout or the log see them
+  let content = conversation_log.redactor.redact(&content);
+  output.emit(Event::ToolOutput {
+    id: &tool_call.id,
+    output: &content,
+  })?;
+  output.emit(Event::ToolFinished {
+    id: &tool_call.id,
+    name: &tool_call.function.name,
+    success,
+  })?;
+  conversation_log
+    .add_tool_result(tool_call, &content, outcome)
+    .await?;
+  messages.push(llm_client::Message::Tool {
+    tool_call_id: tool_call.id.clone(),
+    content,
+  });
   Ok(())
 }
 
@@ -247,18 +1118,26 @@ async fn main() -> Result<()> {
 struct ConversationLog {
   file_path: Option<PathBuf>,
   entries: Vec<serde_json::Value>,
+  redactor: Arc<redact::Redactor>,
 }
 
 impl ConversationLog {
-  fn new(file_path: &Option<PathBuf>) -> Self {
+  fn new(file_path: &Option<PathBuf>, redactor: Arc<redact::Redactor>) -> Self {
     Self {
       file_path: file_path.clone(),
       entries: Vec::new(),
+      redactor,
     }
   }
 
+  // Nothing is logged before its secrets are redacted
+  fn push(&mut self, mut entry: serde_json::Value) {
+    self.redactor.redact_json(&mut entry);
+    self.entries.push(entry);
+  }
+
   async fn add_message(&mut self, message: &llm_client::Message) -> Result<()> {
-    self.entries.push(serde_json::json!({
+    self.push(serde_json::json!({
         "type": "message",
         "timestamp": chrono::Utc::now().to_rfc3339(),
         "message": message,
@@ -268,7 +1147,7 @@ impl ConversationLog {
   }
 
   async fn add_tool_call(&mut self, tool_call: &llm_client::ToolCall) -> Result<()> {
-    self.entries.push(serde_json::json!({
+    self.push(serde_json::json!({
         "type": "tool_call",
         "timestamp": chrono::Utc::now().to_rfc3339(),
         "tool_call": tool_call,
@@ -280,13 +1159,107 @@ impl ConversationLog {
     &mut self,
     tool_call: &llm_client::ToolCall,
     output: &str,
+    outcome: Option<&executor::ToolOutcome>,
   ) -> Result<()> {
-    self.entries.push(serde_json::json!({
+    // The output as the model saw it, and what the command actually did
+    self.push(serde_json::json!({
         "type": "tool_result",
         "timestamp": chrono::Utc::now().to_rfc3339(),
         "tool_call_id": tool_call.id,
         "tool_name": tool_call.function.name,
         "output": output,
+        "outcome": outcome,
+    }));
+    self.save().await
+  }
+
+  async fn add_review(
+    &mut self,
+    tool_call: &llm_client::ToolCall,
+    review: &approval::Review,
+  ) -> Result<()> {
+    let (approved, reason) = match review {
+      approval::Review::Approved => (true, None),
+      approval::Review::Denied(reason) => (false, Some(reason)),
+    };
+    self.push(serde_json::json!({
+        "type": "approval",
+        "timestamp": chrono::Utc::now().to_rfc3339(),
+        "tool_call_id": tool_call.id,
+        "tool_name": tool_call.function.name,
+        "approved": approved,
+        "reason": reason,
+    }));
+    self.save().await
+  }
+
+  async fn add_dry_run(
+    &mut self,
+    tool_call: &llm_client::ToolCall,
+    invocation: &executor::Invocation,
+    approval: Option<&str>,
+  ) -> Result<()> {
+    self.push(serde_json::json!({
+        "type": "dry_run",
+        "timestamp": chrono::Utc::now().to_rfc3339(),
+        "tool_call_id": tool_call.id,
+        "tool_name": tool_call.function.name,
+        "invocation": invocation,
+        "approval": approval,
+    }));
+    self.save().await
+  }
+
+  async fn add_diagnostic(&mut self, turn: usize, message: &str) -> Result<()> {
+    self.push(serde_json::json!({
+        "type": "diagnostic",
+        "timestamp": chrono::Utc::now().to_rfc3339(),
+        "turn": turn,
+        "message": message,
+    }));
+    self.save().await
+  }
+
+  async fn add_usage(&mut self, turn: usize, usage: &TokenUsage, cost: Option<f64>) -> Result<()> {
+    self.push(serde_json::json!({
+        "type": "usage",
+        "timestamp": chrono::Utc::now().to_rfc3339(),
+        "turn": turn,
+        "usage": usage,
+        "cost": cost,
+    }));
+    self.save().await
+  }
+
+  async fn add_compaction(
+    &mut self,
+    turn: usize,
+    method: &str,
+    messages: usize,
+    tokens_before: u64,
+    tokens_after: u64,
+    summary: Option<&str>,
+  ) -> Result<()> {
+    self.push(serde_json::json!({
+        "type": "compaction",
+        "timestamp": chrono::Utc::now().to_rfc3339(),
+        "turn": turn,
+        "method": method,
+        "messages": messages,
+        "tokens_before": tokens_before,
+        "tokens_after": tokens_after,
+        "summary": summary,
+    }));
+    self.save().await
+  }
+
+  async fn add_usage_summary(&mut self, tracker: &UsageTracker) -> Result<()> {
+    self.push(serde_json::json!({
+        "type": "usage_summary",
+        "timestamp": chrono::Utc::now().to_rfc3339(),
+        "turns": tracker.turns,
+        "usage": tracker.total,
+        "cost": tracker.total_cost(),
     }));
     self.save().await
   }
@@ -302,7 +1275,7 @@ impl ConversationLog {
           Err(anyhow::anyhow!("Failed to get parent directory"))
         }
       }
-      None => return Ok(()), // No logging file specified
+      None => Ok(()), // No logging file specified
     }
   }
 }
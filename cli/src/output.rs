@@ -0,0 +1,381 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::io::Write;
+use std::path::Path;
+
+use crate::executor::Invocation;
+use crate::usage::TokenUsage;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+  /// Assistant text on stdout, progress on stderr
+  #[default]
+  Text,
+  /// One JSON event per line on stdout
+  Ndjson,
+}
+
+// Events describing a run, written as NDJSON for programs driving llmcli
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event<'a> {
+  TextDelta {
+    text: &'a str,
+  },
+  ReasoningDelta {
+    text: &'a str,
+  },
+  ToolCallStarted {
+    id: &'a str,
+    name: &'a str,
+    arguments: &'a serde_json::Value,
+  },
+  ToolOutput {
+    id: &'a str,
+    output: &'a str,
+  },
+  ToolFinished {
+    id: &'a str,
+    name: &'a str,
+    success: bool,
+  },
+  // A call the dry run didn't execute, with what it would have run
+  DryRun {
+    id: &'a str,
+    name: &'a str,
+    #[serde(flatten)]
+    invocation: &'a Invocation,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    approval: Option<&'a str>,
+  },
+  Usage {
+    turn: usize,
+    #[serde(flatten)]
+    usage: TokenUsage,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cost: Option<f64>,
+  },
+  // The turn's response broke off and is started over, discard what it streamed so far
+  TurnRetry {
+    turn: usize,
+    reason: &'a str,
+  },
+  TurnEnd {
+    turn: usize,
+    tool_calls: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    finish_reason: Option<&'a str>,
+  },
+  Final {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output: Option<&'a serde_json::Value>,
+  },
+}
+
+// Decides what goes where. Stdout only ever carries the answer (or the event stream), everything
+// else goes to stderr so that llmcli can be piped into other programs.
+pub struct Output {
+  format: OutputFormat,
+  quiet: bool,
+  // With a structured result expected, assistant text is progress rather than the answer
+  structured: bool,
+  show_reasoning: bool,
+  mid_line: bool,
+  in_reasoning: bool,
+  // Whether the turn's text already went to stdout, where it can't be taken back
+  answer_printed: bool,
+  stdout: Box<dyn Write + Send>,
+}
+
+impl Output {
+  pub fn new(format: OutputFormat, quiet: bool, structured: bool, show_reasoning: bool) -> Self {
+    Self {
+      format,
+      quiet,
+      structured,
+      show_reasoning,
+      mid_line: false,
+      in_reasoning: false,
+      answer_printed: false,
+      stdout: Box::new(std::io::stdout()),
+    }
+  }
+
+  // Where the answer goes instead of stdout
+  #[cfg(test)]
+  fn with_stdout(mut self, stdout: impl Write + Send + 'static) -> Self {
+    self.stdout = Box::new(stdout);
+    self
+  }
+
+  pub fn emit(&mut self, event: Event) -> Result<()> {
+    match self.format {
+      // Quiet leaves the answer, and the plan of a dry run
+      OutputFormat::Ndjson
+        if self.quiet && !matches!(event, Event::Final { .. } | Event::DryRun { .. }) => {}
+      OutputFormat::Ndjson => {
+        serde_json::to_writer(&mut self.stdout, &event)?;
+        writeln!(self.stdout)?;
+        self.stdout.flush()?;
+      }
+      OutputFormat::Text => self.render(event)?,
+    }
+    Ok(())
+  }
+
+  // Whether a turn whose stream broke off can be started over without its text showing up twice
+  pub fn can_retry_turn(&self) -> bool {
+    !self.answer_printed
+  }
+
+  // Human readable progress, never on stdout
+  pub fn status(&mut self, message: &str) {
+    if !self.quiet {
+      self.end_line();
+      eprintln!("{}", message);
+    }
+  }
+
+  fn render(&mut self, event: Event) -> Result<()> {
+    match event {
+      Event::ReasoningDelta { text } if self.show_reasoning && !self.quiet => {
+        if !self.in_reasoning {
+          self.end_line();
+          eprintln!("--- Reasoning ---");
+          self.in_reasoning = true;
+        }
+        eprint!("{}", text);
+        std::io::stderr().flush()?;
+      }
+      Event::TextDelta { text } if !self.quiet => {
+        self.end_reasoning();
+        if self.structured {
+          eprint!("{}", text);
+          std::io::stderr().flush()?;
+        } else {
+          write!(self.stdout, "{}", text)?;
+          self.stdout.flush()?;
+          self.answer_printed = true;
+        }
+        self.mid_line = !text.ends_with('\n');
+      }
+      Event::ToolCallStarted {
+        id,
+        name,
+        arguments,
+      } => self.status(&format!(
+        "Tool: {} ({})\nArguments: {}",
+        name, id, arguments
+      )),
+      Event::DryRun {
+        invocation,
+        approval,
+        ..
+      } => {
+        let mut plan = format!(
+          "Dry run, not executed\nShell: {}\nWorking directory: {}\n",
+          invocation.shell,
+          invocation.cwd.display()
+        );
+        for (key, value) in &invocation.env {
+          plan.push_str(&format!("Env: {}={:?}\n", key, value));
+        }
+        plan.push_str(&format!("Command: {}", invocation.command));
+        if let Some(reason) = approval {
+          plan.push_str(&format!("\nNeeds approval: {}", reason));
+        }
+        // The plan is what a dry run is for, so it's shown even when quiet
+        self.end_line();
+        eprintln!("{}", plan);
+      }
+      Event::ToolOutput { output, .. } => self.status(&format!("Output:\n{}", output)),
+      Event::TurnRetry { .. } | Event::TurnEnd { .. } => {
+        self.end_line();
+        self.answer_printed = false;
+      }
+      Event::Final {
+        output: Some(output),
+        ..
+      } => {
+        self.end_line();
+        writeln!(self.stdout, "{}", serde_json::to_string_pretty(output)?)?;
+      }
+      // The text was already streamed unless we're quiet
+      Event::Final {
+        text: Some(text), ..
+      } if self.quiet => writeln!(self.stdout, "{}", text)?,
+      _ => {}
+    }
+    Ok(())
+  }
+
+  fn end_reasoning(&mut self) {
+    if self.in_reasoning {
+      eprintln!("\n--- End reasoning ---");
+      self.in_reasoning = false;
+    }
+  }
+
+  fn end_line(&mut self) {
+    self.end_reasoning();
+    if self.mid_line {
+      if self.structured {
+        eprintln!();
+      } else {
+        let _ = writeln!(self.stdout);
+      }
+      self.mid_line = false;
+    }
+  }
+}
+
+// A JSON schema the final answer has to satisfy
+pub struct OutputSchema {
+  pub schema: serde_json::Value,
+  validator: jsonschema::Validator,
+}
+
+impl OutputSchema {
+  pub fn from_file(path: &Path) -> Result<Self> {
+    let contents = std::fs::read_to_string(path)
+      .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+    Self::new(serde_json::from_str(&contents)?)
+  }
+
+  pub fn new(schema: serde_json::Value) -> Result<Self> {
+    let validator = jsonschema::validator_for(&schema)
+      .map_err(|e| anyhow::anyhow!("Invalid output schema: {}", e))?;
+    Ok(Self { schema, validator })
+  }
+
+  // Parses the answer, tolerating a surrounding markdown code fence, and returns every
+  // validation error on failure so they can be shown to the model
+  pub fn validate(&self, text: &str) -> Result<serde_json::Value, Vec<String>> {
+    let json = strip_code_fence(text.trim());
+    let value: serde_json::Value =
+      serde_json::from_str(json).map_err(|e| vec![format!("Not valid JSON: {}", e)])?;
+
+    let errors: Vec<String> = self
+      .validator
+      .iter_errors(&value)
+      .map(|e| match e.instance_path().as_str() {
+        "" => e.to_string(),
+        path => format!("{}: {}", path, e),
+      })
+      .collect();
+
+    if errors.is_empty() {
+      Ok(value)
+    } else {
+      Err(errors)
+    }
+  }
+}
+
+fn strip_code_fence(text: &str) -> &str {
+  text
+    .strip_prefix("```")
+    .and_then(|rest| rest.strip_suffix("```"))
+    .map(|inner| inner.trim_start_matches("json").trim())
+    .unwrap_or(text)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_output_schema_validate() {
+    let schema = OutputSchema::new(serde_json::json!({
+      "type": "object",
+      "properties": { "count": { "type": "integer" } },
+      "required": ["count"],
+    }))
+    .unwrap();
+
+    assert_eq!(schema.validate(r#"{"count": 3}"#).unwrap()["count"], 3);
+    assert!(schema.validate("```json\n{\"count\": 3}\n```").is_ok());
+
+    let errors = schema.validate(r#"{"count": "three"}"#).unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].starts_with("/count"));
+    assert!(schema.validate("three").is_err());
+  }
+
+  #[test]
+  fn test_event_serialization() {
+    let event = Event::ToolCallStarted {
+      id: "call_1",
+      name: "echo",
+      arguments: &serde_json::json!({ "message": "hi" }),
+    };
+    assert_eq!(
+      serde_json::to_string(&event).unwrap(),
+      r#"{"type":"tool_call_started","id":"call_1","name":"echo","arguments":{"message":"hi"}}"#
+    );
+  }
+
+  // Collects what would go to stdout
+  #[derive(Clone, Default)]
+  struct Buffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+  impl Write for Buffer {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+      self.0.lock().unwrap().write(data)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+      Ok(())
+    }
+  }
+
+  impl Buffer {
+    fn text(&self) -> String {
+      String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+  }
+
+  #[test]
+  fn test_turn_is_not_retried_once_printed() {
+    let stdout = Buffer::default();
+    let mut output =
+      Output::new(OutputFormat::Text, false, false, false).with_stdout(stdout.clone());
+    assert!(output.can_retry_turn());
+    output.emit(Event::TextDelta { text: "The ans" }).unwrap();
+    assert!(!output.can_retry_turn());
+    assert_eq!(stdout.text(), "The ans");
+    output
+      .emit(Event::TurnEnd {
+        turn: 1,
+        tool_calls: 1,
+        finish_reason: None,
+      })
+      .unwrap();
+    assert!(output.can_retry_turn());
+
+    // Events can be taken back by whoever reads them, and progress on stderr doesn't matter
+    let events = Buffer::default();
+    let mut ndjson =
+      Output::new(OutputFormat::Ndjson, false, false, false).with_stdout(events.clone());
+    ndjson.emit(Event::TextDelta { text: "{}" }).unwrap();
+    assert!(ndjson.can_retry_turn());
+    assert_eq!(events.text(), "{\"type\":\"text_delta\",\"text\":\"{}\"}\n");
+  }
+
+  #[test]
+  fn test_quiet_events_are_only_the_answer() {
+    let events = Buffer::default();
+    let mut quiet =
+      Output::new(OutputFormat::Ndjson, true, false, false).with_stdout(events.clone());
+    quiet.emit(Event::TextDelta { text: "42" }).unwrap();
+    quiet
+      .emit(Event::Final {
+        text: Some("42"),
+        output: None,
+      })
+      .unwrap();
+    assert_eq!(events.text(), "{\"type\":\"final\",\"text\":\"42\"}\n");
+  }
+}
@@ -0,0 +1,310 @@
+use anyhow::Result;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// Which tool calls may run, set in the config file's `policy:` block. The first rule matching a
+// call decides, calls no rule matches get the default action. Misspelt fields are errors, as
+// ignoring them would change what a rule matches.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyConfig {
+  #[serde(default)]
+  pub default: Action,
+  #[serde(default)]
+  pub rules: Vec<Rule>,
+  /// Calls allowed per run, of the tools matching each glob together
+  #[serde(default)]
+  pub quotas: Vec<Quota>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+  #[default]
+  Allow,
+  Deny,
+  RequireApproval,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+  /// Glob of the tool names the rule applies to, all tools if not given
+  pub tool: Option<String>,
+  /// Conditions on the arguments, all of which must hold
+  #[serde(default)]
+  pub when: HashMap<String, Condition>,
+  pub action: Action,
+  /// Told to the model when the call is denied
+  pub reason: Option<String>,
+}
+
+// A condition on an argument's value. Everything given must hold, and a missing argument holds
+// no condition.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Condition {
+  pub glob: Option<String>,
+  pub regex: Option<String>,
+  pub equals: Option<serde_json::Value>,
+  pub lt: Option<f64>,
+  pub le: Option<f64>,
+  pub gt: Option<f64>,
+  pub ge: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Quota {
+  pub tool: String,
+  pub max_calls: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+  Allow,
+  Deny(String),
+  RequireApproval(String),
+}
+
+// The policy with its globs and regexes compiled, and the calls counted against its quotas
+pub struct Policy {
+  default: Action,
+  rules: Vec<CompiledRule>,
+  quotas: Vec<(Regex, Quota, usize)>,
+}
+
+struct CompiledRule {
+  tool: Option<Regex>,
+  when: Vec<(String, CompiledCondition)>,
+  action: Action,
+  reason: String,
+}
+
+struct CompiledCondition {
+  patterns: Vec<Regex>,
+  condition: Condition,
+}
+
+impl Policy {
+  pub fn new(config: &PolicyConfig) -> Result<Self> {
+    let rules = config
+      .rules
+      .iter()
+      .enumerate()
+      .map(|(i, rule)| {
+        let when = rule
+          .when
+          .iter()
+          .map(|(argument, condition)| {
+            if condition.is_empty() {
+              anyhow::bail!(
+                "Rule {}: the condition on {} checks nothing",
+                i + 1,
+                argument
+              );
+            }
+            let patterns = condition
+              .glob
+              .iter()
+              .map(|glob| glob_regex(glob))
+              .chain(condition.regex.iter().map(|regex| Regex::new(regex)))
+              .collect::<Result<_, _>>()?;
+            let condition = CompiledCondition {
+              patterns,
+              condition: condition.clone(),
+            };
+            Ok((argument.clone(), condition))
+          })
+          .collect::<Result<_>>()?;
+        Ok(CompiledRule {
+          tool: rule.tool.as_deref().map(glob_regex).transpose()?,
+          when,
+          action: rule.action,
+          reason: rule
+            .reason
+            .clone()
+            .unwrap_or_else(|| format!("rule {}", i + 1)),
+        })
+      })
+      .collect::<Result<_>>()?;
+    let quotas = config
+      .quotas
+      .iter()
+      .map(|quota| Ok((glob_regex(&quota.tool)?, quota.clone(), 0)))
+      .collect::<Result<_>>()?;
+
+    Ok(Self {
+      default: config.default,
+      rules,
+      quotas,
+    })
+  }
+
+  pub fn evaluate(&self, tool: &str, arguments: &serde_json::Value) -> Decision {
+    if let Some((_, quota, _)) = self
+      .quotas
+      .iter()
+      .find(|(glob, quota, calls)| glob.is_match(tool) && *calls >= quota.max_calls)
+    {
+      return Decision::Deny(format!(
+        "{} allows only {} calls per run",
+        quota.tool, quota.max_calls
+      ));
+    }
+
+    let rule = self.rules.iter().find(|rule| {
+      rule.tool.as_ref().is_none_or(|glob| glob.is_match(tool))
+        && rule
+          .when
+          .iter()
+          .all(|(argument, condition)| condition.holds(arguments.get(argument)))
+    });
+    let (action, reason) = match rule {
+      Some(rule) => (rule.action, rule.reason.clone()),
+      None => (self.default, "the default action".to_string()),
+    };
+    match action {
+      Action::Allow => Decision::Allow,
+      Action::Deny => Decision::Deny(reason),
+      Action::RequireApproval => Decision::RequireApproval(reason),
+    }
+  }
+
+  // Counts a call that is going to run against the quotas
+  pub fn record_call(&mut self, tool: &str) {
+    for (glob, _, calls) in &mut self.quotas {
+      if glob.is_match(tool) {
+        *calls += 1;
+      }
+    }
+  }
+}
+
+impl Condition {
+  fn is_empty(&self) -> bool {
+    self.glob.is_none()
+      && self.regex.is_none()
+      && self.equals.is_none()
+      && self.lt.is_none()
+      && self.le.is_none()
+      && self.gt.is_none()
+      && self.ge.is_none()
+  }
+}
+
+impl CompiledCondition {
+  fn holds(&self, value: Option<&serde_json::Value>) -> bool {
+    let Some(value) = value else {
+      return false;
+    };
+    let text = match value {
+      serde_json::Value::String(text) => text.clone(),
+      value => value.to_string(),
+    };
+    let number = match value {
+      serde_json::Value::Number(number) => number.as_f64(),
+      serde_json::Value::String(text) => text.trim().parse().ok(),
+      _ => None,
+    };
+    let compare = |bound: Option<f64>, holds: fn(f64, f64) -> bool| {
+      bound.is_none_or(|bound| number.is_some_and(|number| holds(number, bound)))
+    };
+
+    let condition = &self.condition;
+    self.patterns.iter().all(|pattern| pattern.is_match(&text))
+      && condition
+        .equals
+        .as_ref()
+        .is_none_or(|equals| equals == value)
+      && compare(condition.lt, |a, b| a < b)
+      && compare(condition.le, |a, b| a <= b)
+      && compare(condition.gt, |a, b| a > b)
+      && compare(condition.ge, |a, b| a >= b)
+  }
+}
+
+// `*` and `?` match within a path segment, `**` across them
+fn glob_regex(glob: &str) -> Result<Regex, regex::Error> {
+  let mut pattern = String::from("^");
+  let mut chars = glob.chars().peekable();
+  while let Some(c) = chars.next() {
+    match c {
+      '*' if chars.peek() == Some(&'*') => {
+        chars.next();
+        pattern.push_str(".*");
+      }
+      '*' => pattern.push_str("[^/]*"),
+      '?' => pattern.push_str("[^/]"),
+      c => pattern.push_str(&regex::escape(&c.to_string())),
+    }
+  }
+  pattern.push('$');
+  Regex::new(&pattern)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn test_policy_rules_and_quotas() {
+    let config: PolicyConfig = serde_yaml::from_str(
+      r#"
+rules:
+  - tool: write_*
+    when:
+      path: { glob: "/etc/**" }
+    action: deny
+    reason: "/etc is off limits"
+  - tool: scale
+    when:
+      replicas: { gt: 10 }
+    action: require_approval
+  - tool: shell
+    when:
+      command: { regex: "rm\\s+-rf" }
+    action: deny
+quotas:
+  - tool: scale
+    max_calls: 1
+"#,
+    )
+    .unwrap();
+    let mut policy = Policy::new(&config).unwrap();
+
+    assert_eq!(
+      policy.evaluate("write_file", &json!({ "path": "/etc/nginx/nginx.conf" })),
+      Decision::Deny("/etc is off limits".to_string())
+    );
+    assert_eq!(
+      policy.evaluate("write_file", &json!({ "path": "/tmp/etc" })),
+      Decision::Allow
+    );
+    assert_eq!(
+      policy.evaluate("scale", &json!({ "replicas": "20" })),
+      Decision::RequireApproval("rule 2".to_string())
+    );
+    assert_eq!(
+      policy.evaluate("shell", &json!({ "command": "rm  -rf /" })),
+      Decision::Deny("rule 3".to_string())
+    );
+    assert_eq!(
+      policy.evaluate("scale", &json!({ "replicas": 3 })),
+      Decision::Allow
+    );
+    policy.record_call("scale");
+    assert!(matches!(
+      policy.evaluate("scale", &json!({ "replicas": 3 })),
+      Decision::Deny(_)
+    ));
+
+    let misspelt = "rules:\n  - when:\n      path: { regexp: \"^/tmp\" }\n    action: allow";
+    assert!(serde_yaml::from_str::<PolicyConfig>(misspelt).is_err());
+    let empty: PolicyConfig =
+      serde_yaml::from_str("rules:\n  - when:\n      path: {}\n    action: allow").unwrap();
+    assert!(Policy::new(&empty).is_err());
+  }
+}
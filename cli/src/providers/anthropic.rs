@@ -0,0 +1,588 @@
+use anyhow::Result;
+use eventsource_stream::Eventsource;
+use futures::StreamExt;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+
+use crate::llm_client::{
+  Delta, EventStream, LlmRequest, Message, PromptTokensDetails, Reasoning, ReasoningEffort,
+  StreamChoice, StreamChunk, StreamEvent, ToolCallChunk, ToolCallFunctionChunk, ToolChoice, Usage,
+};
+
+pub const API_VERSION: &str = "2023-06-01";
+
+// There's no response format, so a schema-constrained answer is requested as the input of a
+// forced tool call, which the stream translator turns back into text
+const OUTPUT_TOOL: &str = "final_answer";
+
+// The messages API requires max_tokens on every request
+const DEFAULT_MAX_TOKENS: u32 = 8192;
+
+pub fn request_body(request: &LlmRequest) -> Result<serde_json::Value> {
+  let mut system = Vec::new();
+  let mut messages: Vec<serde_json::Value> = Vec::new();
+
+  for message in &request.messages {
+    match message {
+      Message::System { content } => system.push(content.as_str()),
+      Message::User { content } => messages.push(json!({
+        "role": "user",
+        "content": content,
+      })),
+      Message::Assistant {
+        content,
+        reasoning,
+        tool_calls,
+      } => {
+        // Thinking has to be passed back unchanged, and only signed thinking is accepted
+        let mut blocks: Vec<_> = reasoning
+          .iter()
+          .filter_map(|reasoning| match reasoning {
+            Reasoning {
+              redacted: Some(data),
+              ..
+            } => Some(json!({ "type": "redacted_thinking", "data": data })),
+            Reasoning {
+              text,
+              signature: Some(signature),
+              ..
+            } => Some(json!({ "type": "thinking", "thinking": text, "signature": signature })),
+            _ => None,
+          })
+          .collect();
+        if let Some(text) = content.as_deref().filter(|text| !text.is_empty()) {
+          blocks.push(json!({ "type": "text", "text": text }));
+        }
+        for call in tool_calls.iter().flatten() {
+          let input = match &call.function.arguments {
+            serde_json::Value::Null => json!({}),
+            arguments => arguments.clone(),
+          };
+          blocks.push(json!({
+            "type": "tool_use",
+            "id": call.id,
+            "name": call.function.name,
+            "input": input,
+          }));
+        }
+        if blocks
+          .iter()
+          .any(|block| !block["type"].as_str().is_some_and(is_thinking))
+        {
+          messages.push(json!({ "role": "assistant", "content": blocks }));
+        }
+      }
+      Message::Tool {
+        tool_call_id,
+        content,
+      } => {
+        let block = json!({
+          "type": "tool_result",
+          "tool_use_id": tool_call_id,
+          "content": content,
+        });
+        // All results for an assistant turn have to go back in a single user message
+        match messages.last_mut() {
+          Some(last) if last["role"] == "user" && last["content"].is_array() => {
+            if let Some(blocks) = last["content"].as_array_mut() {
+              blocks.push(block);
+            }
+          }
+          _ => messages.push(json!({ "role": "user", "content": [block] })),
+        }
+      }
+    }
+  }
+
+  let mut tools: Vec<_> = request
+    .tools
+    .iter()
+    .map(|tool| {
+      json!({
+        "name": tool.function.name,
+        "description": tool.function.description,
+        "input_schema": tool.function.parameters,
+      })
+    })
+    .collect();
+
+  let mut tool_choice = request.tool_choice.clone();
+  if let Some(schema) = &request.output_schema {
+    tools.push(json!({
+      "name": OUTPUT_TOOL,
+      "description": "Respond with the final answer",
+      "input_schema": schema,
+    }));
+    tool_choice = Some(ToolChoice::Tool(OUTPUT_TOOL.to_string()));
+  }
+
+  let params = &request.params;
+  let thinking_budget = params
+    .thinking_budget
+    .or(params.reasoning_effort.map(effort_budget));
+  let max_tokens = match (params.max_tokens, thinking_budget) {
+    (Some(max_tokens), _) => max_tokens,
+    // The budget is part of max_tokens, so leave room for the answer
+    (None, Some(budget)) => budget + DEFAULT_MAX_TOKENS,
+    (None, None) => DEFAULT_MAX_TOKENS,
+  };
+
+  let mut body = json!({
+    "model": request.model,
+    "max_tokens": max_tokens,
+    "messages": messages,
+    "stream": request.stream,
+  });
+  if !system.is_empty() {
+    body["system"] = json!(system.join("\n\n"));
+  }
+  if !tools.is_empty() {
+    body["tools"] = json!(tools);
+  }
+  if let Some(budget) = thinking_budget {
+    body["thinking"] = json!({ "type": "enabled", "budget_tokens": budget });
+    if params.temperature.is_some() {
+      tracing::warn!("temperature can't be changed with extended thinking enabled, ignoring it");
+    }
+  } else if let Some(temperature) = params.temperature {
+    body["temperature"] = json!(temperature);
+  }
+  if let Some(choice) = &tool_choice
+    && !tools.is_empty()
+  {
+    body["tool_choice"] = match choice {
+      // Forcing tool use isn't allowed together with extended thinking
+      ToolChoice::Required | ToolChoice::Tool(_) if thinking_budget.is_some() => {
+        tracing::warn!("can't force tool use with extended thinking enabled, using auto");
+        json!({ "type": "auto" })
+      }
+      ToolChoice::Auto => json!({ "type": "auto" }),
+      ToolChoice::None => json!({ "type": "none" }),
+      ToolChoice::Required => json!({ "type": "any" }),
+      ToolChoice::Tool(name) => json!({ "type": "tool", "name": name }),
+    };
+  }
+  if let Some(top_p) = params.top_p {
+    body["top_p"] = json!(top_p);
+  }
+  if !params.stop.is_empty() {
+    body["stop_sequences"] = json!(params.stop);
+  }
+  if params.seed.is_some() {
+    tracing::warn!("seed is not supported by the Anthropic API, ignoring it");
+  }
+
+  Ok(body)
+}
+
+fn is_thinking(block_type: &str) -> bool {
+  block_type == "thinking" || block_type == "redacted_thinking"
+}
+
+fn effort_budget(effort: ReasoningEffort) -> u32 {
+  match effort {
+    ReasoningEffort::Minimal => 1024,
+    ReasoningEffort::Low => 4096,
+    ReasoningEffort::Medium => 10000,
+    ReasoningEffort::High => 32000,
+  }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum StreamMessage {
+  MessageStart {
+    message: MessageInfo,
+  },
+  ContentBlockStart {
+    index: usize,
+    content_block: ContentBlock,
+  },
+  ContentBlockDelta {
+    index: usize,
+    delta: BlockDelta,
+  },
+  MessageDelta {
+    delta: Option<MessageDeltaInfo>,
+    usage: Option<ApiUsage>,
+  },
+  MessageStop,
+  Error {
+    error: ApiError,
+  },
+  #[serde(other)]
+  Other,
+}
+
+#[derive(Debug, Deserialize)]
+struct MessageInfo {
+  usage: Option<ApiUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MessageDeltaInfo {
+  stop_reason: Option<String>,
+}
+
+// Input tokens are reported once at the start, output tokens cumulatively in message deltas
+#[derive(Debug, Clone, Default, Deserialize)]
+struct ApiUsage {
+  input_tokens: Option<u64>,
+  output_tokens: Option<u64>,
+  cache_read_input_tokens: Option<u64>,
+  cache_creation_input_tokens: Option<u64>,
+}
+
+impl ApiUsage {
+  fn update(&mut self, other: ApiUsage) {
+    self.input_tokens = other.input_tokens.or(self.input_tokens);
+    self.output_tokens = other.output_tokens.or(self.output_tokens);
+    self.cache_read_input_tokens = other
+      .cache_read_input_tokens
+      .or(self.cache_read_input_tokens);
+    self.cache_creation_input_tokens = other
+      .cache_creation_input_tokens
+      .or(self.cache_creation_input_tokens);
+  }
+
+  // Anthropic's input tokens exclude cached ones, unlike OpenAI's prompt tokens
+  fn to_usage(&self) -> Usage {
+    let cached = self.cache_read_input_tokens.unwrap_or_default();
+    Usage {
+      prompt_tokens: self.input_tokens.unwrap_or_default()
+        + cached
+        + self.cache_creation_input_tokens.unwrap_or_default(),
+      completion_tokens: self.output_tokens.unwrap_or_default(),
+      prompt_tokens_details: Some(PromptTokensDetails {
+        cached_tokens: Some(cached),
+        cache_write_tokens: self.cache_creation_input_tokens,
+      }),
+      completion_tokens_details: None,
+    }
+  }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentBlock {
+  ToolUse {
+    id: String,
+    name: String,
+  },
+  RedactedThinking {
+    data: String,
+  },
+  #[serde(other)]
+  Other,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum BlockDelta {
+  TextDelta {
+    text: String,
+  },
+  InputJsonDelta {
+    partial_json: String,
+  },
+  ThinkingDelta {
+    thinking: String,
+  },
+  SignatureDelta {
+    signature: String,
+  },
+  #[serde(other)]
+  Other,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiError {
+  #[serde(rename = "type")]
+  error_type: String,
+  message: String,
+}
+
+// Anthropic numbers content blocks across text and tool use, while the chunk format numbers
+// tool calls only, so we keep track of which block belongs to which tool call.
+#[derive(Default)]
+struct Translator {
+  tool_indices: HashMap<usize, usize>,
+  output_blocks: HashSet<usize>,
+  usage: ApiUsage,
+}
+
+impl Translator {
+  fn translate(&mut self, message: StreamMessage) -> Option<Result<StreamEvent>> {
+    match message {
+      StreamMessage::MessageStart { message } => {
+        self.usage.update(message.usage.unwrap_or_default());
+        None
+      }
+      StreamMessage::MessageDelta { delta, usage } => {
+        self.usage.update(usage.unwrap_or_default());
+        let choices = delta
+          .and_then(|delta| delta.stop_reason)
+          .map(|reason| StreamChoice {
+            delta: None,
+            finish_reason: Some(finish_reason(&reason).to_string()),
+          });
+        Some(Ok(StreamEvent::Chunk(StreamChunk {
+          choices: choices.into_iter().collect(),
+          usage: Some(self.usage.to_usage()),
+        })))
+      }
+      StreamMessage::ContentBlockStart {
+        index,
+        content_block: ContentBlock::ToolUse { name, .. },
+      } if name == OUTPUT_TOOL => {
+        self.output_blocks.insert(index);
+        None
+      }
+      StreamMessage::ContentBlockStart {
+        index,
+        content_block: ContentBlock::ToolUse { id, name },
+      } => {
+        let tool_index = self.tool_indices.len();
+        self.tool_indices.insert(index, tool_index);
+        Some(Ok(tool_call_chunk(
+          tool_index,
+          Some(id),
+          Some(name),
+          String::new(),
+        )))
+      }
+      StreamMessage::ContentBlockStart {
+        content_block: ContentBlock::RedactedThinking { data },
+        ..
+      } => Some(Ok(StreamEvent::Chunk(chunk(Delta {
+        redacted_reasoning: Some(data),
+        ..Default::default()
+      })))),
+      StreamMessage::ContentBlockDelta {
+        delta: BlockDelta::ThinkingDelta { thinking },
+        ..
+      } => Some(Ok(StreamEvent::Chunk(chunk(Delta {
+        reasoning_content: Some(thinking),
+        ..Default::default()
+      })))),
+      StreamMessage::ContentBlockDelta {
+        delta: BlockDelta::SignatureDelta { signature },
+        ..
+      } => Some(Ok(StreamEvent::Chunk(chunk(Delta {
+        reasoning_signature: Some(signature),
+        ..Default::default()
+      })))),
+      StreamMessage::ContentBlockDelta {
+        delta: BlockDelta::TextDelta { text },
+        ..
+      } => Some(Ok(StreamEvent::Chunk(chunk(Delta {
+        content: Some(text),
+        ..Default::default()
+      })))),
+      StreamMessage::ContentBlockDelta {
+        index,
+        delta: BlockDelta::InputJsonDelta { partial_json },
+      } if self.output_blocks.contains(&index) => Some(Ok(StreamEvent::Chunk(chunk(Delta {
+        content: Some(partial_json),
+        ..Default::default()
+      })))),
+      StreamMessage::ContentBlockDelta {
+        index,
+        delta: BlockDelta::InputJsonDelta { partial_json },
+      } => {
+        let tool_index = *self.tool_indices.get(&index)?;
+        Some(Ok(tool_call_chunk(tool_index, None, None, partial_json)))
+      }
+      StreamMessage::MessageStop => Some(Ok(StreamEvent::Done)),
+      StreamMessage::Error { error } => Some(Err(anyhow::anyhow!(
+        "LLM API error: {} - {}",
+        error.error_type,
+        error.message
+      ))),
+      _ => None,
+    }
+  }
+}
+
+fn chunk(delta: Delta) -> StreamChunk {
+  StreamChunk {
+    choices: vec![StreamChoice {
+      delta: Some(delta),
+      finish_reason: None,
+    }],
+    usage: None,
+  }
+}
+
+fn finish_reason(stop_reason: &str) -> &str {
+  match stop_reason {
+    "end_turn" | "stop_sequence" | "pause_turn" => "stop",
+    "max_tokens" => "length",
+    "tool_use" => "tool_calls",
+    "refusal" => "content_filter",
+    other => other,
+  }
+}
+
+fn tool_call_chunk(
+  index: usize,
+  id: Option<String>,
+  name: Option<String>,
+  arguments: String,
+) -> StreamEvent {
+  StreamEvent::Chunk(chunk(Delta {
+    tool_calls: Some(vec![ToolCallChunk {
+      index: Some(index),
+      id,
+      function: ToolCallFunctionChunk { name, arguments },
+    }]),
+    ..Default::default()
+  }))
+}
+
+pub fn event_stream(response: reqwest::Response) -> EventStream {
+  let mut translator = Translator::default();
+  let stream = response
+    .bytes_stream()
+    .eventsource()
+    .filter_map(move |event| {
+      let event = match event {
+        Ok(event) => {
+          tracing::debug!("Received event data: {}", &event.data);
+          match serde_json::from_str::<StreamMessage>(&event.data) {
+            Ok(message) => translator.translate(message),
+            Err(e) => Some(Err(anyhow::anyhow!("Failed to parse event: {}", e))),
+          }
+        }
+        Err(e) => Some(Err(anyhow::anyhow!("Stream error: {}", e))),
+      };
+      futures::future::ready(event)
+    });
+
+  Box::pin(stream)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::llm_client::{ModelParams, ToolCall, ToolCallFunction};
+
+  #[test]
+  fn test_request_body_translates_roles() {
+    let request = LlmRequest {
+      messages: vec![
+        Message::System {
+          content: "Be brief".to_string(),
+        },
+        Message::User {
+          content: "List files".to_string(),
+        },
+        Message::Assistant {
+          content: None,
+          reasoning: vec![Reasoning {
+            text: "Let me look".to_string(),
+            signature: Some("sig".to_string()),
+            redacted: None,
+          }],
+          tool_calls: Some(vec![ToolCall {
+            id: "call_1".to_string(),
+            tool_type: "function".to_string(),
+            function: ToolCallFunction {
+              name: "list_files".to_string(),
+              arguments: json!({ "path": "." }),
+            },
+          }]),
+        },
+        Message::Tool {
+          tool_call_id: "call_1".to_string(),
+          content: "README.md".to_string(),
+        },
+      ],
+      tools: vec![],
+      model: "claude-3-5-haiku-latest".to_string(),
+      stream: true,
+      params: ModelParams {
+        temperature: Some(0.0),
+        stop: vec!["END".to_string()],
+        reasoning_effort: Some(ReasoningEffort::Low),
+        ..Default::default()
+      },
+      tool_choice: None,
+      output_schema: None,
+    };
+
+    let body = request_body(&request).unwrap();
+    assert_eq!(body["system"], "Be brief");
+    let messages = body["messages"].as_array().unwrap();
+    assert_eq!(messages.len(), 3);
+    assert_eq!(messages[1]["content"][0]["type"], "thinking");
+    assert_eq!(messages[1]["content"][0]["signature"], "sig");
+    assert_eq!(messages[1]["content"][1]["type"], "tool_use");
+    assert_eq!(messages[1]["content"][1]["input"]["path"], ".");
+    assert_eq!(messages[2]["role"], "user");
+    assert_eq!(messages[2]["content"][0]["tool_use_id"], "call_1");
+    assert!(body.get("tools").is_none());
+    assert_eq!(body["thinking"]["budget_tokens"], 4096);
+    assert_eq!(body["max_tokens"], 4096 + DEFAULT_MAX_TOKENS);
+    assert!(body.get("temperature").is_none());
+    assert_eq!(body["stop_sequences"][0], "END");
+  }
+
+  #[test]
+  fn test_translator_numbers_tool_calls() {
+    let mut translator = Translator::default();
+    let events = [
+      r#"{"type":"message_start","message":{"id":"msg_1","usage":{"input_tokens":10,"cache_read_input_tokens":5,"cache_creation_input_tokens":3,"output_tokens":1}}}"#,
+      r#"{"type":"content_block_start","index":0,"content_block":{"type":"text","text":""}}"#,
+      r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Hi"}}"#,
+      r#"{"type":"content_block_start","index":1,"content_block":{"type":"tool_use","id":"toolu_1","name":"echo","input":{}}}"#,
+      r#"{"type":"content_block_delta","index":1,"delta":{"type":"input_json_delta","partial_json":"{}"}}"#,
+      r#"{"type":"message_delta","delta":{"stop_reason":"tool_use"},"usage":{"output_tokens":7}}"#,
+      r#"{"type":"message_stop"}"#,
+    ];
+
+    let chunks: Vec<_> = events
+      .iter()
+      .filter_map(|data| translator.translate(serde_json::from_str(data).unwrap()))
+      .map(|event| event.unwrap())
+      .collect();
+
+    assert_eq!(chunks.len(), 5);
+    match &chunks[2] {
+      StreamEvent::Chunk(chunk) => {
+        let call = &chunk.choices[0]
+          .delta
+          .as_ref()
+          .unwrap()
+          .tool_calls
+          .as_ref()
+          .unwrap()[0];
+        assert_eq!(call.index, Some(0));
+        assert_eq!(call.function.arguments, "{}");
+      }
+      StreamEvent::Done => panic!("expected a chunk"),
+    }
+    match &chunks[3] {
+      StreamEvent::Chunk(chunk) => {
+        let usage = chunk.usage.as_ref().unwrap();
+        assert_eq!(usage.prompt_tokens, 18);
+        assert_eq!(
+          usage
+            .prompt_tokens_details
+            .as_ref()
+            .unwrap()
+            .cache_write_tokens,
+          Some(3)
+        );
+        assert_eq!(usage.completion_tokens, 7);
+        assert_eq!(
+          chunk.choices[0].finish_reason.as_deref(),
+          Some("tool_calls")
+        );
+      }
+      StreamEvent::Done => panic!("expected a chunk"),
+    }
+    assert!(matches!(chunks[4], StreamEvent::Done));
+  }
+}
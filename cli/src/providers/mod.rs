@@ -0,0 +1,142 @@
+mod anthropic;
+mod ollama;
+mod openai;
+mod responses;
+mod text_tools;
+
+use anyhow::Result;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::llm_client::{EventStream, LlmRequest};
+
+// The API flavour spoken by the configured endpoint. Requests are built from the provider
+// neutral `LlmRequest`, and every provider's stream is translated into OpenAI-style chunks so
+// the conversation loop only has to deal with one shape.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderKind {
+  #[default]
+  #[serde(rename = "openai")]
+  OpenAi,
+  // Chat completions on an Azure OpenAI deployment
+  Azure,
+  // The OpenAI Responses API
+  Responses,
+  Anthropic,
+  // Ollama's native chat API, streaming NDJSON
+  Ollama,
+}
+
+// How tools are offered to the model: through the API's function calling, or described in the
+// system prompt with calls parsed out of the answer, for models without function calling
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolProtocol {
+  #[default]
+  Native,
+  Text,
+}
+
+impl ToolProtocol {
+  pub fn request_body(
+    &self,
+    provider: ProviderKind,
+    request: &LlmRequest,
+  ) -> Result<serde_json::Value> {
+    match self {
+      ToolProtocol::Native => provider.request_body(request),
+      ToolProtocol::Text => provider.request_body(&text_tools::rewrite_request(request)),
+    }
+  }
+
+  pub fn event_stream(&self, provider: ProviderKind, response: reqwest::Response) -> EventStream {
+    match self {
+      ToolProtocol::Native => provider.event_stream(response),
+      ToolProtocol::Text => text_tools::parse_tool_calls(provider.event_stream(response)),
+    }
+  }
+}
+
+// Azure requires an api-version on every request
+const AZURE_API_VERSION: &str = "2024-10-21";
+
+static CALL_IDS: AtomicUsize = AtomicUsize::new(1);
+
+// An id for a tool call whose API doesn't give it one, unique for the whole run so that the events
+// and the log can tell calls of different turns apart
+fn next_call_id() -> String {
+  format!("call_{}", CALL_IDS.fetch_add(1, Ordering::Relaxed))
+}
+
+impl FromStr for ProviderKind {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    match s.to_lowercase().as_str() {
+      "openai" => Ok(ProviderKind::OpenAi),
+      "azure" => Ok(ProviderKind::Azure),
+      "responses" => Ok(ProviderKind::Responses),
+      "anthropic" => Ok(ProviderKind::Anthropic),
+      "ollama" => Ok(ProviderKind::Ollama),
+      _ => anyhow::bail!("Unknown provider: {}", s),
+    }
+  }
+}
+
+impl ProviderKind {
+  pub fn request_body(&self, request: &LlmRequest) -> Result<serde_json::Value> {
+    match self {
+      ProviderKind::OpenAi | ProviderKind::Azure => openai::request_body(request),
+      ProviderKind::Responses => responses::request_body(request),
+      ProviderKind::Anthropic => anthropic::request_body(request),
+      ProviderKind::Ollama => ollama::request_body(request),
+    }
+  }
+
+  pub fn auth_header(&self, token: &str) -> Result<(HeaderName, HeaderValue)> {
+    match self {
+      // Ollama itself has no auth, but proxies in front of it may
+      ProviderKind::OpenAi | ProviderKind::Responses | ProviderKind::Ollama => Ok((
+        HeaderName::from_static("authorization"),
+        HeaderValue::from_str(&format!("Bearer {}", token))?,
+      )),
+      ProviderKind::Azure => Ok((
+        HeaderName::from_static("api-key"),
+        HeaderValue::from_str(token)?,
+      )),
+      ProviderKind::Anthropic => Ok((
+        HeaderName::from_static("x-api-key"),
+        HeaderValue::from_str(token)?,
+      )),
+    }
+  }
+
+  // Headers the API requires that the user shouldn't have to spell out
+  pub fn default_headers(&self, headers: &mut HeaderMap) {
+    if *self == ProviderKind::Anthropic && !headers.contains_key("anthropic-version") {
+      headers.insert(
+        "anthropic-version",
+        HeaderValue::from_static(anthropic::API_VERSION),
+      );
+    }
+  }
+
+  pub fn default_api_version(&self) -> Option<&'static str> {
+    match self {
+      ProviderKind::Azure => Some(AZURE_API_VERSION),
+      _ => None,
+    }
+  }
+
+  pub fn event_stream(&self, response: reqwest::Response) -> EventStream {
+    match self {
+      ProviderKind::OpenAi | ProviderKind::Azure => openai::event_stream(response),
+      ProviderKind::Responses => responses::event_stream(response),
+      ProviderKind::Anthropic => anthropic::event_stream(response),
+      ProviderKind::Ollama => ollama::event_stream(response),
+    }
+  }
+}
@@ -0,0 +1,341 @@
+use anyhow::Result;
+use futures::StreamExt;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+
+use crate::llm_client::{
+  Delta, EventStream, LlmRequest, Message, StreamChoice, StreamChunk, StreamEvent, ToolCallChunk,
+  ToolCallFunctionChunk, ToolChoice, Usage,
+};
+
+// Ollama's native `/api/chat`, which takes tool call arguments as objects, refers to tool calls by
+// name rather than id, and keeps sampling parameters under `options`
+pub fn request_body(request: &LlmRequest) -> Result<serde_json::Value> {
+  let mut tool_names = HashMap::new();
+  let mut messages = Vec::new();
+
+  for message in &request.messages {
+    match message {
+      Message::System { content } => messages.push(json!({ "role": "system", "content": content })),
+      Message::User { content } => messages.push(json!({ "role": "user", "content": content })),
+      Message::Assistant {
+        content,
+        tool_calls,
+        ..
+      } => {
+        let calls: Vec<_> = tool_calls
+          .iter()
+          .flatten()
+          .map(|call| {
+            tool_names.insert(call.id.as_str(), call.function.name.as_str());
+            let arguments = match &call.function.arguments {
+              serde_json::Value::Null => json!({}),
+              arguments => arguments.clone(),
+            };
+            json!({ "function": { "name": call.function.name, "arguments": arguments } })
+          })
+          .collect();
+        let mut message = json!({
+          "role": "assistant",
+          "content": content.as_deref().unwrap_or_default(),
+        });
+        if !calls.is_empty() {
+          message["tool_calls"] = json!(calls);
+        }
+        messages.push(message);
+      }
+      Message::Tool {
+        tool_call_id,
+        content,
+      } => messages.push(json!({
+        "role": "tool",
+        "content": content,
+        "tool_name": tool_names.get(tool_call_id.as_str()),
+      })),
+    }
+  }
+
+  let mut body = json!({
+    "model": request.model,
+    "messages": messages,
+    "stream": request.stream,
+  });
+  if !request.tools.is_empty() && request.tool_choice != Some(ToolChoice::None) {
+    body["tools"] = json!(request.tools);
+    if matches!(
+      request.tool_choice,
+      Some(ToolChoice::Required | ToolChoice::Tool(_))
+    ) {
+      tracing::warn!("Ollama can't force tool use, leaving it to the model");
+    }
+  }
+  if let Some(schema) = &request.output_schema {
+    body["format"] = schema.clone();
+  }
+
+  let params = &request.params;
+  let mut options = serde_json::Map::new();
+  if let Some(temperature) = params.temperature {
+    options.insert("temperature".to_string(), json!(temperature));
+  }
+  if let Some(top_p) = params.top_p {
+    options.insert("top_p".to_string(), json!(top_p));
+  }
+  if let Some(max_tokens) = params.max_tokens {
+    options.insert("num_predict".to_string(), json!(max_tokens));
+  }
+  if !params.stop.is_empty() {
+    options.insert("stop".to_string(), json!(params.stop));
+  }
+  if let Some(seed) = params.seed {
+    options.insert("seed".to_string(), json!(seed));
+  }
+  if !options.is_empty() {
+    body["options"] = json!(options);
+  }
+  if params.reasoning_effort.is_some() || params.thinking_budget.is_some() {
+    body["think"] = json!(true);
+  }
+
+  Ok(body)
+}
+
+// One line of the NDJSON stream
+#[derive(Debug, Deserialize)]
+struct ChatChunk {
+  message: Option<ChatMessage>,
+  #[serde(default)]
+  done: bool,
+  done_reason: Option<String>,
+  prompt_eval_count: Option<u64>,
+  eval_count: Option<u64>,
+  error: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatMessage {
+  #[serde(default)]
+  content: String,
+  thinking: Option<String>,
+  #[serde(default)]
+  tool_calls: Vec<ChatToolCall>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatToolCall {
+  function: ChatFunction,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatFunction {
+  name: String,
+  #[serde(default)]
+  arguments: serde_json::Value,
+}
+
+// Tool calls arrive whole and without ids, so they're numbered as they come and given ids
+#[derive(Default)]
+struct Translator {
+  tool_calls: usize,
+}
+
+impl Translator {
+  fn translate(&mut self, chunk: ChatChunk) -> Vec<Result<StreamEvent>> {
+    if let Some(error) = chunk.error {
+      return vec![Err(anyhow::anyhow!("LLM API error: {}", error))];
+    }
+
+    let mut delta = Delta::default();
+    if let Some(message) = chunk.message {
+      if !message.content.is_empty() {
+        delta.content = Some(message.content);
+      }
+      delta.reasoning_content = message.thinking.filter(|thinking| !thinking.is_empty());
+      if !message.tool_calls.is_empty() {
+        let calls = message
+          .tool_calls
+          .into_iter()
+          .map(|call| {
+            let index = self.tool_calls;
+            self.tool_calls += 1;
+            ToolCallChunk {
+              index: Some(index),
+              id: Some(super::next_call_id()),
+              function: ToolCallFunctionChunk {
+                name: Some(call.function.name),
+                arguments: call.function.arguments.to_string(),
+              },
+            }
+          })
+          .collect();
+        delta.tool_calls = Some(calls);
+      }
+    }
+
+    let mut choice = StreamChoice {
+      delta: Some(delta),
+      finish_reason: None,
+    };
+    let mut usage = None;
+    if chunk.done {
+      choice.finish_reason = Some(match chunk.done_reason.as_deref() {
+        Some("length") => "length".to_string(),
+        _ if self.tool_calls > 0 => "tool_calls".to_string(),
+        _ => "stop".to_string(),
+      });
+      usage = Some(Usage {
+        prompt_tokens: chunk.prompt_eval_count.unwrap_or_default(),
+        completion_tokens: chunk.eval_count.unwrap_or_default(),
+        prompt_tokens_details: None,
+        completion_tokens_details: None,
+      });
+    }
+
+    let mut events = vec![Ok(StreamEvent::Chunk(StreamChunk {
+      choices: vec![choice],
+      usage,
+    }))];
+    if chunk.done {
+      events.push(Ok(StreamEvent::Done));
+    }
+    events
+  }
+}
+
+pub fn event_stream(response: reqwest::Response) -> EventStream {
+  let mut translator = Translator::default();
+  let mut buffer = Vec::new();
+  let stream = response.bytes_stream().flat_map(move |bytes| {
+    let mut events = Vec::new();
+    match bytes {
+      Ok(bytes) => {
+        buffer.extend_from_slice(&bytes);
+        while let Some(end) = buffer.iter().position(|&byte| byte == b'\n') {
+          let line: Vec<u8> = buffer.drain(..=end).collect();
+          let line = String::from_utf8_lossy(&line);
+          if line.trim().is_empty() {
+            continue;
+          }
+          tracing::debug!("Received chunk data: {}", line.trim());
+          match serde_json::from_str::<ChatChunk>(&line) {
+            Ok(chunk) => events.extend(translator.translate(chunk)),
+            Err(e) => events.push(Err(anyhow::anyhow!("Failed to parse chunk: {}", e))),
+          }
+        }
+      }
+      Err(e) => events.push(Err(anyhow::anyhow!("Stream error: {}", e))),
+    }
+    futures::stream::iter(events)
+  });
+
+  Box::pin(stream)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::llm_client::{ModelParams, ToolCall, ToolCallFunction};
+
+  #[test]
+  fn test_request_body_names_tool_results() {
+    let request = LlmRequest {
+      messages: vec![
+        Message::User {
+          content: "List files".to_string(),
+        },
+        Message::Assistant {
+          content: None,
+          reasoning: vec![],
+          tool_calls: Some(vec![ToolCall {
+            id: "call_0".to_string(),
+            tool_type: "function".to_string(),
+            function: ToolCallFunction {
+              name: "list_files".to_string(),
+              arguments: json!({ "path": "." }),
+            },
+          }]),
+        },
+        Message::Tool {
+          tool_call_id: "call_0".to_string(),
+          content: "README.md".to_string(),
+        },
+      ],
+      tools: vec![],
+      model: "qwen3".to_string(),
+      stream: true,
+      params: ModelParams {
+        max_tokens: Some(256),
+        temperature: Some(0.7),
+        ..Default::default()
+      },
+      tool_choice: None,
+      output_schema: None,
+    };
+
+    let body = request_body(&request).unwrap();
+    let messages = body["messages"].as_array().unwrap();
+    assert_eq!(
+      messages[1]["tool_calls"][0]["function"]["arguments"]["path"],
+      "."
+    );
+    assert_eq!(messages[2]["tool_name"], "list_files");
+    assert_eq!(body["options"]["num_predict"], 256);
+    assert_eq!(body["options"]["temperature"].to_string(), "0.7");
+  }
+
+  #[test]
+  fn test_translator_numbers_tool_calls() {
+    let mut translator = Translator::default();
+    let lines = [
+      r#"{"message":{"role":"assistant","content":"","thinking":"Hm"},"done":false}"#,
+      r#"{"message":{"role":"assistant","content":"","tool_calls":[{"function":{"name":"echo","arguments":{"text":"hi"}}},{"function":{"name":"pwd","arguments":{}}}]},"done":false}"#,
+      r#"{"message":{"role":"assistant","content":""},"done":true,"done_reason":"stop","prompt_eval_count":12,"eval_count":5}"#,
+    ];
+
+    let events: Vec<_> = lines
+      .iter()
+      .flat_map(|line| translator.translate(serde_json::from_str(line).unwrap()))
+      .map(|event| event.unwrap())
+      .collect();
+
+    assert_eq!(events.len(), 4);
+    let StreamEvent::Chunk(chunk) = &events[1] else {
+      panic!("expected a chunk");
+    };
+    let calls = chunk.choices[0]
+      .delta
+      .as_ref()
+      .unwrap()
+      .tool_calls
+      .as_ref()
+      .unwrap();
+    assert_eq!(calls[1].index, Some(1));
+    assert_ne!(calls[0].id, calls[1].id);
+    // Ids aren't reused by later turns
+    let later = Translator::default().translate(serde_json::from_str(lines[1]).unwrap());
+    let Ok(StreamEvent::Chunk(later)) = &later[0] else {
+      panic!("expected a chunk");
+    };
+    let later = later.choices[0]
+      .delta
+      .as_ref()
+      .unwrap()
+      .tool_calls
+      .as_ref()
+      .unwrap();
+    assert_eq!(later[0].index, Some(0));
+    assert!(calls.iter().all(|call| call.id != later[0].id));
+    assert_eq!(calls[0].function.arguments, r#"{"text":"hi"}"#);
+
+    let StreamEvent::Chunk(chunk) = &events[2] else {
+      panic!("expected a chunk");
+    };
+    assert_eq!(chunk.usage.as_ref().unwrap().prompt_tokens, 12);
+    assert_eq!(
+      chunk.choices[0].finish_reason.as_deref(),
+      Some("tool_calls")
+    );
+    assert!(matches!(events[3], StreamEvent::Done));
+  }
+}
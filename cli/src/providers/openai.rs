@@ -0,0 +1,178 @@
+use anyhow::Result;
+use eventsource_stream::Eventsource;
+use futures::StreamExt;
+use serde_json::json;
+
+use crate::llm_client::{EventStream, LlmRequest, StreamChunk, StreamEvent, ToolChoice};
+
+// The neutral request types already follow the chat completions format
+pub fn request_body(request: &LlmRequest) -> Result<serde_json::Value> {
+  let mut body = serde_json::to_value(request)?;
+  let params = &request.params;
+
+  // Servers differ on whether reasoning may be sent back, and none of them need it
+  for message in body["messages"].as_array_mut().into_iter().flatten() {
+    if let Some(message) = message.as_object_mut() {
+      message.remove("reasoning");
+    }
+  }
+
+  // Usage is only reported for streams when asked for, in a final chunk without choices
+  if request.stream {
+    body["stream_options"] = json!({ "include_usage": true });
+  }
+
+  if let Some(temperature) = params.temperature {
+    body["temperature"] = json!(temperature);
+  }
+  if let Some(top_p) = params.top_p {
+    body["top_p"] = json!(top_p);
+  }
+  if !params.stop.is_empty() {
+    body["stop"] = json!(params.stop);
+  }
+  if let Some(seed) = params.seed {
+    body["seed"] = json!(seed);
+  }
+  if let Some(effort) = params.reasoning_effort {
+    body["reasoning_effort"] = json!(effort);
+  }
+  if let Some(max_tokens) = params.max_tokens {
+    // Reasoning models only accept the newer name, while many compatible servers only know the
+    // older one
+    if params.reasoning_effort.is_some() {
+      body["max_completion_tokens"] = json!(max_tokens);
+    } else {
+      body["max_tokens"] = json!(max_tokens);
+    }
+  }
+  if let Some(choice) = &request.tool_choice
+    && !request.tools.is_empty()
+  {
+    body["tool_choice"] = match choice {
+      ToolChoice::Tool(name) => json!({ "type": "function", "function": { "name": name } }),
+      choice => json!(choice),
+    };
+  }
+  if let Some(schema) = &request.output_schema {
+    body["response_format"] = json!({
+      "type": "json_schema",
+      "json_schema": { "name": "output", "schema": schema, "strict": is_strict_schema(schema) },
+    });
+  }
+  if params.thinking_budget.is_some() {
+    tracing::warn!("thinking_budget is not supported by this provider, use reasoning_effort");
+  }
+
+  Ok(body)
+}
+
+// Strict mode is rejected for schemas with an object that allows additional properties or
+// doesn't require all of its properties, so it's only asked for when the schema allows it
+pub fn is_strict_schema(schema: &serde_json::Value) -> bool {
+  let Some(object) = schema.as_object() else {
+    return true;
+  };
+  let is_object = object.contains_key("properties")
+    || match object.get("type") {
+      Some(serde_json::Value::Array(types)) => types.contains(&json!("object")),
+      kind => kind == Some(&json!("object")),
+    };
+  if is_object {
+    let required: Vec<&str> = object
+      .get("required")
+      .and_then(|required| required.as_array())
+      .map(|required| required.iter().filter_map(|name| name.as_str()).collect())
+      .unwrap_or_default();
+    let properties = object.get("properties").and_then(|p| p.as_object());
+    if object.get("additionalProperties") != Some(&json!(false))
+      || properties.is_some_and(|p| p.keys().any(|name| !required.contains(&name.as_str())))
+    {
+      return false;
+    }
+  }
+  object.iter().all(|(key, value)| match key.as_str() {
+    "properties" | "$defs" | "definitions" => value
+      .as_object()
+      .is_none_or(|schemas| schemas.values().all(is_strict_schema)),
+    "anyOf" | "allOf" | "oneOf" => value
+      .as_array()
+      .is_none_or(|schemas| schemas.iter().all(is_strict_schema)),
+    "items" => is_strict_schema(value),
+    _ => true,
+  })
+}
+
+pub fn event_stream(response: reqwest::Response) -> EventStream {
+  let stream = response.bytes_stream().eventsource().map(|event| {
+    match event {
+      Ok(event) => {
+        // Parse SSE event data
+        let data = event.data;
+        if data == "[DONE]" {
+          tracing::debug!("Received done event");
+          Ok(StreamEvent::Done)
+        } else {
+          tracing::debug!("Received chunk data: {}", &data);
+          parse_chunk(&data)
+        }
+      }
+      Err(e) => Err(anyhow::anyhow!("Stream error: {}", e)),
+    }
+  });
+
+  Box::pin(stream)
+}
+
+// Errors that happen after the response started arrive as a chunk of their own
+fn parse_chunk(data: &str) -> Result<StreamEvent> {
+  let value: serde_json::Value =
+    serde_json::from_str(data).map_err(|e| anyhow::anyhow!("Failed to parse chunk: {}", e))?;
+  if let Some(error) = value.get("error") {
+    let message = error.get("message").and_then(|m| m.as_str());
+    anyhow::bail!(
+      "LLM API error: {}",
+      message.map_or_else(|| error.to_string(), String::from)
+    );
+  }
+  let chunk = serde_json::from_value::<StreamChunk>(value)
+    .map_err(|e| anyhow::anyhow!("Failed to parse chunk: {}", e))?;
+  Ok(StreamEvent::Chunk(chunk))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_strict_only_for_strict_schemas() {
+    let strict = json!({
+      "type": "object",
+      "properties": {
+        "name": { "type": "string" },
+        "tags": {
+          "type": "array",
+          "items": {
+            "type": "object",
+            "properties": { "key": { "type": "string" } },
+            "required": ["key"],
+            "additionalProperties": false,
+          },
+        },
+      },
+      "required": ["name", "tags"],
+      "additionalProperties": false,
+    });
+    assert!(is_strict_schema(&strict));
+
+    let mut optional = strict.clone();
+    optional["required"] = json!(["name"]);
+    assert!(!is_strict_schema(&optional));
+    let mut open = strict.clone();
+    open["properties"]["tags"]["items"]
+      .as_object_mut()
+      .unwrap()
+      .remove("additionalProperties");
+    assert!(!is_strict_schema(&open));
+  }
+}
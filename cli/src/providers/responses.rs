@@ -0,0 +1,419 @@
+use anyhow::Result;
+use eventsource_stream::Eventsource;
+use futures::StreamExt;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+
+use crate::llm_client::{
+  CompletionTokensDetails, Delta, EventStream, LlmRequest, Message, PromptTokensDetails,
+  StreamChoice, StreamChunk, StreamEvent, ToolCallChunk, ToolCallFunctionChunk, ToolChoice, Usage,
+};
+
+// The OpenAI Responses API takes the conversation as a list of items, where tool calls and their
+// results are items of their own rather than parts of messages
+pub fn request_body(request: &LlmRequest) -> Result<serde_json::Value> {
+  let mut instructions = Vec::new();
+  let mut input = Vec::new();
+
+  for message in &request.messages {
+    match message {
+      Message::System { content } => instructions.push(content.as_str()),
+      Message::User { content } => input.push(json!({ "role": "user", "content": content })),
+      // Reasoning can only be passed back encrypted, which needs stored responses or an opt-in
+      Message::Assistant {
+        content,
+        tool_calls,
+        ..
+      } => {
+        if let Some(text) = content.as_deref().filter(|text| !text.is_empty()) {
+          input.push(json!({ "role": "assistant", "content": text }));
+        }
+        for call in tool_calls.iter().flatten() {
+          let arguments = match &call.function.arguments {
+            serde_json::Value::Null => "{}".to_string(),
+            arguments => arguments.to_string(),
+          };
+          input.push(json!({
+            "type": "function_call",
+            "call_id": call.id,
+            "name": call.function.name,
+            "arguments": arguments,
+          }));
+        }
+      }
+      Message::Tool {
+        tool_call_id,
+        content,
+      } => input.push(json!({
+        "type": "function_call_output",
+        "call_id": tool_call_id,
+        "output": content,
+      })),
+    }
+  }
+
+  let mut body = json!({
+    "model": request.model,
+    "input": input,
+    "stream": request.stream,
+    "store": false,
+  });
+  if !instructions.is_empty() {
+    body["instructions"] = json!(instructions.join("\n\n"));
+  }
+  if !request.tools.is_empty() {
+    body["tools"] = request
+      .tools
+      .iter()
+      .map(|tool| {
+        json!({
+          "type": "function",
+          "name": tool.function.name,
+          "description": tool.function.description,
+          "parameters": tool.function.parameters,
+        })
+      })
+      .collect();
+    if let Some(choice) = &request.tool_choice {
+      body["tool_choice"] = match choice {
+        ToolChoice::Tool(name) => json!({ "type": "function", "name": name }),
+        choice => json!(choice),
+      };
+    }
+  }
+  if let Some(schema) = &request.output_schema {
+    body["text"] = json!({
+      "format": {
+        "type": "json_schema",
+        "name": "output",
+        "schema": schema,
+        "strict": super::openai::is_strict_schema(schema),
+      },
+    });
+  }
+
+  let params = &request.params;
+  if let Some(temperature) = params.temperature {
+    body["temperature"] = json!(temperature);
+  }
+  if let Some(top_p) = params.top_p {
+    body["top_p"] = json!(top_p);
+  }
+  if let Some(max_tokens) = params.max_tokens {
+    body["max_output_tokens"] = json!(max_tokens);
+  }
+  if let Some(effort) = params.reasoning_effort {
+    body["reasoning"] = json!({ "effort": effort });
+  }
+  if !params.stop.is_empty() {
+    tracing::warn!("stop sequences are not supported by the Responses API, ignoring them");
+  }
+  if params.seed.is_some() {
+    tracing::warn!("seed is not supported by the Responses API, ignoring it");
+  }
+  if params.thinking_budget.is_some() {
+    tracing::warn!("thinking_budget is not supported by this provider, use reasoning_effort");
+  }
+
+  Ok(body)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type")]
+enum ResponseEvent {
+  #[serde(rename = "response.output_text.delta")]
+  TextDelta { delta: String },
+  #[serde(rename = "response.refusal.delta")]
+  RefusalDelta { delta: String },
+  #[serde(
+    rename = "response.reasoning_summary_text.delta",
+    alias = "response.reasoning_text.delta"
+  )]
+  ReasoningDelta { delta: String },
+  #[serde(rename = "response.output_item.added")]
+  OutputItemAdded {
+    output_index: usize,
+    item: OutputItem,
+  },
+  #[serde(rename = "response.function_call_arguments.delta")]
+  ArgumentsDelta { output_index: usize, delta: String },
+  #[serde(rename = "response.completed", alias = "response.incomplete")]
+  Completed { response: ResponseInfo },
+  #[serde(rename = "response.failed")]
+  Failed { response: ResponseInfo },
+  #[serde(rename = "error")]
+  Error {
+    code: Option<String>,
+    message: String,
+  },
+  #[serde(other)]
+  Other,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum OutputItem {
+  FunctionCall {
+    call_id: String,
+    name: String,
+  },
+  #[serde(other)]
+  Other,
+}
+
+#[derive(Debug, Deserialize)]
+struct ResponseInfo {
+  usage: Option<ApiUsage>,
+  incomplete_details: Option<IncompleteDetails>,
+  error: Option<ApiError>,
+}
+
+#[derive(Debug, Deserialize)]
+struct IncompleteDetails {
+  reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiError {
+  code: Option<String>,
+  message: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiUsage {
+  input_tokens: u64,
+  output_tokens: u64,
+  input_tokens_details: Option<PromptTokensDetails>,
+  output_tokens_details: Option<CompletionTokensDetails>,
+}
+
+impl From<ApiUsage> for Usage {
+  fn from(usage: ApiUsage) -> Self {
+    Usage {
+      prompt_tokens: usage.input_tokens,
+      completion_tokens: usage.output_tokens,
+      prompt_tokens_details: usage.input_tokens_details,
+      completion_tokens_details: usage.output_tokens_details,
+    }
+  }
+}
+
+// Output items are numbered across messages, reasoning and function calls, while the chunk
+// format numbers tool calls only
+#[derive(Default)]
+struct Translator {
+  tool_indices: HashMap<usize, usize>,
+}
+
+impl Translator {
+  fn translate(&mut self, event: ResponseEvent) -> Vec<Result<StreamEvent>> {
+    let delta = match event {
+      ResponseEvent::TextDelta { delta } => Delta {
+        content: Some(delta),
+        ..Default::default()
+      },
+      ResponseEvent::RefusalDelta { delta } => Delta {
+        refusal: Some(delta),
+        ..Default::default()
+      },
+      ResponseEvent::ReasoningDelta { delta } => Delta {
+        reasoning_content: Some(delta),
+        ..Default::default()
+      },
+      ResponseEvent::OutputItemAdded {
+        output_index,
+        item: OutputItem::FunctionCall { call_id, name },
+      } => {
+        let index = self.tool_indices.len();
+        self.tool_indices.insert(output_index, index);
+        tool_call_delta(index, Some(call_id), Some(name), String::new())
+      }
+      ResponseEvent::ArgumentsDelta {
+        output_index,
+        delta,
+      } => match self.tool_indices.get(&output_index) {
+        Some(&index) => tool_call_delta(index, None, None, delta),
+        None => return vec![],
+      },
+      ResponseEvent::Completed { response } => {
+        let finish_reason = match response.incomplete_details.and_then(|d| d.reason) {
+          Some(reason) if reason == "max_output_tokens" => "length".to_string(),
+          Some(reason) => reason,
+          None if !self.tool_indices.is_empty() => "tool_calls".to_string(),
+          None => "stop".to_string(),
+        };
+        let chunk = StreamChunk {
+          choices: vec![StreamChoice {
+            delta: None,
+            finish_reason: Some(finish_reason),
+          }],
+          usage: response.usage.map(Usage::from),
+        };
+        return vec![Ok(StreamEvent::Chunk(chunk)), Ok(StreamEvent::Done)];
+      }
+      ResponseEvent::Failed { response } => {
+        let message = match response.error {
+          Some(error) => format!("{} - {}", error.code.unwrap_or_default(), error.message),
+          None => "the response failed".to_string(),
+        };
+        return vec![Err(anyhow::anyhow!("LLM API error: {}", message))];
+      }
+      ResponseEvent::Error { code, message } => {
+        return vec![Err(anyhow::anyhow!(
+          "LLM API error: {} - {}",
+          code.unwrap_or_default(),
+          message
+        ))];
+      }
+      _ => return vec![],
+    };
+
+    vec![Ok(StreamEvent::Chunk(StreamChunk {
+      choices: vec![StreamChoice {
+        delta: Some(delta),
+        finish_reason: None,
+      }],
+      usage: None,
+    }))]
+  }
+}
+
+fn tool_call_delta(
+  index: usize,
+  id: Option<String>,
+  name: Option<String>,
+  arguments: String,
+) -> Delta {
+  Delta {
+    tool_calls: Some(vec![ToolCallChunk {
+      index: Some(index),
+      id,
+      function: ToolCallFunctionChunk { name, arguments },
+    }]),
+    ..Default::default()
+  }
+}
+
+pub fn event_stream(response: reqwest::Response) -> EventStream {
+  let mut translator = Translator::default();
+  let stream = response
+    .bytes_stream()
+    .eventsource()
+    .flat_map(move |event| {
+      let events = match event {
+        Ok(event) => {
+          tracing::debug!("Received event data: {}", &event.data);
+          match serde_json::from_str::<ResponseEvent>(&event.data) {
+            Ok(event) => translator.translate(event),
+            Err(e) => vec![Err(anyhow::anyhow!("Failed to parse event: {}", e))],
+          }
+        }
+        Err(e) => vec![Err(anyhow::anyhow!("Stream error: {}", e))],
+      };
+      futures::stream::iter(events)
+    });
+
+  Box::pin(stream)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::llm_client::{ModelParams, ToolCall, ToolCallFunction};
+
+  #[test]
+  fn test_request_body_uses_items() {
+    let request = LlmRequest {
+      messages: vec![
+        Message::System {
+          content: "Be brief".to_string(),
+        },
+        Message::User {
+          content: "List files".to_string(),
+        },
+        Message::Assistant {
+          content: None,
+          reasoning: vec![],
+          tool_calls: Some(vec![ToolCall {
+            id: "call_1".to_string(),
+            tool_type: "function".to_string(),
+            function: ToolCallFunction {
+              name: "list_files".to_string(),
+              arguments: json!({ "path": "." }),
+            },
+          }]),
+        },
+        Message::Tool {
+          tool_call_id: "call_1".to_string(),
+          content: "README.md".to_string(),
+        },
+      ],
+      tools: vec![],
+      model: "gpt-5".to_string(),
+      stream: true,
+      params: ModelParams {
+        max_tokens: Some(1000),
+        ..Default::default()
+      },
+      tool_choice: Some(ToolChoice::Required),
+      output_schema: None,
+    };
+
+    let body = request_body(&request).unwrap();
+    assert_eq!(body["instructions"], "Be brief");
+    let input = body["input"].as_array().unwrap();
+    assert_eq!(input.len(), 3);
+    assert_eq!(input[1]["type"], "function_call");
+    assert_eq!(input[1]["arguments"], r#"{"path":"."}"#);
+    assert_eq!(input[2]["type"], "function_call_output");
+    assert_eq!(input[2]["call_id"], "call_1");
+    assert_eq!(body["max_output_tokens"], 1000);
+    assert!(body.get("tool_choice").is_none());
+  }
+
+  #[test]
+  fn test_translator_numbers_tool_calls() {
+    let mut translator = Translator::default();
+    let events = [
+      r#"{"type":"response.created","response":{"id":"resp_1"}}"#,
+      r#"{"type":"response.output_item.added","output_index":0,"item":{"type":"message","role":"assistant"}}"#,
+      r#"{"type":"response.output_text.delta","output_index":0,"delta":"Hi"}"#,
+      r#"{"type":"response.output_item.added","output_index":1,"item":{"type":"function_call","call_id":"call_1","name":"echo","arguments":""}}"#,
+      r#"{"type":"response.function_call_arguments.delta","output_index":1,"delta":"{}"}"#,
+      r#"{"type":"response.completed","response":{"usage":{"input_tokens":10,"input_tokens_details":{"cached_tokens":4},"output_tokens":7}}}"#,
+    ];
+
+    let chunks: Vec<_> = events
+      .iter()
+      .flat_map(|data| translator.translate(serde_json::from_str(data).unwrap()))
+      .map(|event| event.unwrap())
+      .collect();
+
+    assert_eq!(chunks.len(), 5);
+    let StreamEvent::Chunk(chunk) = &chunks[2] else {
+      panic!("expected a chunk");
+    };
+    let call = &chunk.choices[0]
+      .delta
+      .as_ref()
+      .unwrap()
+      .tool_calls
+      .as_ref()
+      .unwrap()[0];
+    assert_eq!(call.index, Some(0));
+    assert_eq!(call.function.arguments, "{}");
+
+    let StreamEvent::Chunk(chunk) = &chunks[3] else {
+      panic!("expected a chunk");
+    };
+    let usage = chunk.usage.as_ref().unwrap();
+    assert_eq!(usage.prompt_tokens, 10);
+    assert_eq!(usage.completion_tokens, 7);
+    assert_eq!(
+      chunk.choices[0].finish_reason.as_deref(),
+      Some("tool_calls")
+    );
+    assert!(matches!(chunks[4], StreamEvent::Done));
+  }
+}
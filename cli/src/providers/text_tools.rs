@@ -0,0 +1,284 @@
+use futures::StreamExt;
+use regex::Regex;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+use crate::llm_client::{
+  EventStream, LlmRequest, Message, StreamChoice, StreamChunk, StreamEvent, ToolCallChunk,
+  ToolCallFunctionChunk, ToolChoice,
+};
+
+// Tool calling for models without native support: the tools are described in the system prompt,
+// the model writes its calls into its answer, and the results go back as user messages
+const CALL_START: &str = "<tool_call>";
+const CALL_END: &str = "</tool_call>";
+
+const INSTRUCTIONS: &str = "You can call the tools listed below. To call one, write the call \
+in exactly this format, with the arguments as a JSON object matching the tool's parameters:
+
+<tool_call>
+{\"name\": \"tool_name\", \"arguments\": {\"parameter\": \"value\"}}
+</tool_call>
+
+You can make several calls in one response. Then stop and wait: the results will come back in \
+<tool_result> blocks in the next message. Never write tool results yourself.";
+
+static CALL_NAME: LazyLock<Regex> =
+  LazyLock::new(|| Regex::new(r#""name"\s*:\s*"([^"]*)""#).unwrap());
+
+// Turns a request with tools, or with tool calls in its history, into one without them
+pub fn rewrite_request(request: &LlmRequest) -> LlmRequest {
+  let mut prompt = String::from(INSTRUCTIONS);
+  prompt.push_str("\n\n<tools>\n");
+  for tool in &request.tools {
+    let tool = json!({
+      "name": tool.function.name,
+      "description": tool.function.description,
+      "parameters": tool.function.parameters,
+    });
+    prompt.push_str(&format!("{}\n", tool));
+  }
+  prompt.push_str("</tools>");
+  match &request.tool_choice {
+    Some(ToolChoice::Required) => prompt.push_str("\n\nCall at least one tool now."),
+    Some(ToolChoice::Tool(name)) => prompt.push_str(&format!("\n\nCall the {} tool now.", name)),
+    Some(ToolChoice::None) => prompt.push_str("\n\nDon't call any tools now, answer directly."),
+    Some(ToolChoice::Auto) | None => {}
+  }
+
+  let mut messages: Vec<Message> = Vec::new();
+  let mut tool_names = HashMap::new();
+  for message in &request.messages {
+    let message = match message {
+      Message::Assistant {
+        content,
+        reasoning,
+        tool_calls: Some(tool_calls),
+      } => {
+        let mut text = content.clone().unwrap_or_default();
+        for call in tool_calls {
+          tool_names.insert(call.id.as_str(), call.function.name.as_str());
+          let arguments = match &call.function.arguments {
+            serde_json::Value::Null => json!({}),
+            arguments => arguments.clone(),
+          };
+          let call = json!({ "name": call.function.name, "arguments": arguments });
+          text.push_str(&format!("\n\n{}\n{}\n{}", CALL_START, call, CALL_END));
+        }
+        Message::Assistant {
+          content: Some(text.trim_start().to_string()),
+          reasoning: reasoning.clone(),
+          tool_calls: None,
+        }
+      }
+      Message::Tool {
+        tool_call_id,
+        content,
+      } => Message::User {
+        content: format!(
+          "<tool_result name=\"{}\">\n{}\n</tool_result>",
+          tool_names.get(tool_call_id.as_str()).unwrap_or(&""),
+          content
+        ),
+      },
+      message => message.clone(),
+    };
+
+    // Results of several calls, and anything said after them, make up a single user turn
+    match (messages.last_mut(), message) {
+      (Some(Message::User { content }), Message::User { content: more }) => {
+        content.push_str("\n\n");
+        content.push_str(&more);
+      }
+      (_, message) => messages.push(message),
+    }
+  }
+
+  match messages.first_mut() {
+    _ if request.tools.is_empty() => {}
+    Some(Message::System { content }) => {
+      content.push_str("\n\n");
+      content.push_str(&prompt);
+    }
+    _ => messages.insert(0, Message::System { content: prompt }),
+  }
+
+  LlmRequest {
+    messages,
+    tools: Vec::new(),
+    tool_choice: None,
+    ..request.clone()
+  }
+}
+
+// Separates the calls from the text of the answer as it streams in. Text that may be the start of
+// a call is held back until it's clear whether it is one.
+#[derive(Default)]
+struct CallParser {
+  buffer: String,
+  in_call: bool,
+  calls: usize,
+}
+
+impl CallParser {
+  fn push(&mut self, text: &str) -> (String, Vec<ToolCallChunk>) {
+    self.buffer.push_str(text);
+    let mut shown = String::new();
+    let mut calls = Vec::new();
+    loop {
+      if self.in_call {
+        let Some(end) = self.buffer.find(CALL_END) else {
+          break;
+        };
+        let call: String = self.buffer.drain(..end + CALL_END.len()).collect();
+        calls.push(self.call(&call[..end]));
+        self.in_call = false;
+      } else if let Some(start) = self.buffer.find(CALL_START) {
+        shown.extend(self.buffer.drain(..start));
+        self.buffer.drain(..CALL_START.len());
+        self.in_call = true;
+      } else {
+        let keep = (1..CALL_START.len())
+          .rev()
+          .find(|&len| self.buffer.ends_with(&CALL_START[..len]))
+          .unwrap_or(0);
+        shown.extend(self.buffer.drain(..self.buffer.len() - keep));
+        break;
+      }
+    }
+    (shown, calls)
+  }
+
+  // Models that stop at the end tag, or run out of tokens, leave the last call open
+  fn finish(&mut self) -> (String, Vec<ToolCallChunk>) {
+    let rest = std::mem::take(&mut self.buffer);
+    if std::mem::take(&mut self.in_call) {
+      (String::new(), vec![self.call(&rest)])
+    } else {
+      (rest, Vec::new())
+    }
+  }
+
+  // Calls that aren't valid JSON are passed on as they are, for the model to be told what's wrong
+  fn call(&mut self, text: &str) -> ToolCallChunk {
+    let text = text.trim();
+    let (name, arguments) = match serde_json::from_str::<serde_json::Value>(text) {
+      Ok(call) => (
+        call["name"].as_str().unwrap_or_default().to_string(),
+        call
+          .get("arguments")
+          .map(|a| a.to_string())
+          .unwrap_or_default(),
+      ),
+      Err(_) => (
+        CALL_NAME
+          .captures(text)
+          .map(|captures| captures[1].to_string())
+          .unwrap_or_default(),
+        text.to_string(),
+      ),
+    };
+    let index = self.calls;
+    self.calls += 1;
+    ToolCallChunk {
+      index: Some(index),
+      id: Some(super::next_call_id()),
+      function: ToolCallFunctionChunk {
+        name: Some(name),
+        arguments,
+      },
+    }
+  }
+}
+
+pub fn parse_tool_calls(stream: EventStream) -> EventStream {
+  let mut parser = CallParser::default();
+  let stream = stream.flat_map(move |event| {
+    let events = match event {
+      Ok(StreamEvent::Chunk(mut chunk)) => {
+        for choice in &mut chunk.choices {
+          let delta = choice.delta.get_or_insert_default();
+          let (mut shown, mut calls) = match delta.content.take() {
+            Some(content) => parser.push(&content),
+            None => Default::default(),
+          };
+          if choice.finish_reason.is_some() {
+            let (rest, rest_calls) = parser.finish();
+            shown.push_str(&rest);
+            calls.extend(rest_calls);
+            if parser.calls > 0 && choice.finish_reason.as_deref() == Some("stop") {
+              choice.finish_reason = Some("tool_calls".to_string());
+            }
+          }
+          delta.content = Some(shown).filter(|shown| !shown.is_empty());
+          if !calls.is_empty() {
+            delta.tool_calls.get_or_insert_default().extend(calls);
+          }
+        }
+        vec![Ok(StreamEvent::Chunk(chunk))]
+      }
+      // Streams without a finish reason end here
+      Ok(StreamEvent::Done) => {
+        let (rest, calls) = parser.finish();
+        let mut events = Vec::new();
+        if !rest.is_empty() || !calls.is_empty() {
+          events.push(Ok(StreamEvent::Chunk(StreamChunk {
+            choices: vec![StreamChoice {
+              delta: Some(crate::llm_client::Delta {
+                content: Some(rest).filter(|rest| !rest.is_empty()),
+                tool_calls: Some(calls).filter(|calls| !calls.is_empty()),
+                ..Default::default()
+              }),
+              finish_reason: None,
+            }],
+            usage: None,
+          })));
+        }
+        events.push(Ok(StreamEvent::Done));
+        events
+      }
+      Err(e) => vec![Err(e)],
+    };
+    futures::stream::iter(events)
+  });
+
+  Box::pin(stream)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_call_parser_splits_calls_from_text() {
+    let mut parser = CallParser::default();
+    let mut shown = String::new();
+    let mut calls = Vec::new();
+    for text in [
+      "Let me look.\n<tool",
+      "_call>\n{\"name\": \"list_files\", \"arguments\": {\"path\": \".\"}}\n</tool_call>",
+      "\n<tool_call>\n{\"name\": \"read_file\", \"arguments\": {\"file_path\": ",
+    ] {
+      let (text, more) = parser.push(text);
+      shown.push_str(&text);
+      calls.extend(more);
+    }
+    let (text, more) = parser.finish();
+    shown.push_str(&text);
+    calls.extend(more);
+
+    assert_eq!(shown.trim(), "Let me look.");
+    assert_eq!(calls.len(), 2);
+    assert_eq!(calls[0].function.name.as_deref(), Some("list_files"));
+    assert_eq!(calls[0].function.arguments, r#"{"path":"."}"#);
+    // The unfinished call is passed on, to fail validation
+    assert_eq!(calls[1].function.name.as_deref(), Some("read_file"));
+    assert!(calls[1].function.arguments.ends_with("\"file_path\":"));
+
+    // A later turn's calls are numbered from 0 again, but don't reuse the ids
+    let (_, later) = CallParser::default().push("<tool_call>{\"name\": \"pwd\"}</tool_call>");
+    assert_eq!(later[0].index, Some(0));
+    assert!(calls.iter().all(|call| call.id != later[0].id));
+  }
+}
@@ -0,0 +1,172 @@
+use anyhow::Result;
+use regex::{Captures, Regex};
+use std::cell::OnceCell;
+use std::collections::HashMap;
+use std::io::{self, Read};
+
+use crate::executor::shell_command;
+
+// Renders a prompt template. Tags are `{{name}}` for variables, `{{file "path"}}` to include a
+// file and `{{cmd "command"}}` to include the output of a shell command. Arguments are either
+// quoted strings or variable names. `{{stdin}}` is the program's standard input, unless a
+// variable of that name is given.
+pub struct Template<'a> {
+  vars: &'a HashMap<String, String>,
+  shell: &'a str,
+  hidden_env: &'a [String],
+  stdin: OnceCell<String>,
+}
+
+impl<'a> Template<'a> {
+  pub fn new(vars: &'a HashMap<String, String>, shell: &'a str) -> Self {
+    Self {
+      vars,
+      shell,
+      hidden_env: &[],
+      stdin: OnceCell::new(),
+    }
+  }
+
+  // Keeps variables such as API tokens out of the commands' environment
+  pub fn with_hidden_env(mut self, vars: &'a [String]) -> Self {
+    self.hidden_env = vars;
+    self
+  }
+
+  pub fn render(&self, template: &str) -> Result<String> {
+    let tag = Regex::new(
+      r#"\{\{\s*([A-Za-z_][A-Za-z0-9_]*)((?:\s+(?:"(?:[^"\\]|\\.)*"|[A-Za-z_][A-Za-z0-9_]*))*)\s*\}\}"#,
+    )?;
+    let arg = Regex::new(r#""((?:[^"\\]|\\.)*)"|([A-Za-z_][A-Za-z0-9_]*)"#)?;
+
+    let mut error = None;
+    let rendered = tag.replace_all(template, |caps: &Captures| {
+      let args: Result<Vec<String>> = arg
+        .captures_iter(&caps[2])
+        .map(|a| match (a.get(1), a.get(2)) {
+          (Some(quoted), _) => Ok(unescape(quoted.as_str())),
+          (_, Some(name)) => self.variable(name.as_str()),
+          _ => unreachable!(),
+        })
+        .collect();
+
+      match args.and_then(|args| self.expand(&caps[1], &args)) {
+        Ok(text) => text,
+        Err(e) => {
+          error.get_or_insert(e);
+          String::new()
+        }
+      }
+    });
+
+    match error {
+      Some(e) => Err(e),
+      None => Ok(rendered.into_owned()),
+    }
+  }
+
+  fn expand(&self, name: &str, args: &[String]) -> Result<String> {
+    match (name, args) {
+      (name, []) => self.variable(name),
+      ("file", [path]) => std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Failed to include file {}: {}", path, e)),
+      ("cmd", [command]) => self.run(command),
+      ("file" | "cmd", _) => anyhow::bail!("{} takes exactly one argument", name),
+      _ => anyhow::bail!("Unknown template function: {}", name),
+    }
+  }
+
+  fn variable(&self, name: &str) -> Result<String> {
+    match self.vars.get(name) {
+      Some(value) => Ok(value.clone()),
+      None if name == "stdin" => {
+        if self.stdin.get().is_none() {
+          let mut input = String::new();
+          io::stdin().read_to_string(&mut input)?;
+          let _ = self.stdin.set(input);
+        }
+        Ok(self.stdin.get().cloned().unwrap_or_default())
+      }
+      None => anyhow::bail!("Undefined template variable: {}", name),
+    }
+  }
+
+  fn run(&self, command: &str) -> Result<String> {
+    let output = shell_command(self.shell, command, self.hidden_env).output()?;
+    if !output.status.success() {
+      anyhow::bail!(
+        "Template command `{}` failed with exit code {:?}\nstderr: {}",
+        command,
+        output.status.code(),
+        String::from_utf8_lossy(&output.stderr)
+      );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+  }
+}
+
+fn unescape(s: &str) -> String {
+  let mut result = String::with_capacity(s.len());
+  let mut chars = s.chars();
+  while let Some(c) = chars.next() {
+    if c != '\\' {
+      result.push(c);
+      continue;
+    }
+    match chars.next() {
+      Some('n') => result.push('\n'),
+      Some(escaped) => result.push(escaped),
+      None => result.push('\\'),
+    }
+  }
+  result
+}
+
+pub fn parse_var(s: &str) -> Result<(String, String), String> {
+  s.split_once('=')
+    .map(|(key, value)| (key.to_string(), value.to_string()))
+    .ok_or_else(|| format!("invalid variable `{}`, expected KEY=VALUE", s))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_render_variables_and_includes() {
+    let vars: HashMap<_, _> = [
+      ("name".to_string(), "world".to_string()),
+      ("greeting".to_string(), "echo hi".to_string()),
+    ]
+    .into_iter()
+    .collect();
+    let template = Template::new(&vars, "sh");
+
+    let rendered = template
+      .render(r#"Hello {{ name }}! {{cmd "printf '%s' \"quoted\""}} {{cmd greeting}}"#)
+      .unwrap();
+    assert_eq!(rendered, "Hello world! quoted hi\n");
+
+    let rendered = template.render(r#"{{file "Cargo.toml"}}"#).unwrap();
+    assert!(rendered.contains("[package]"));
+
+    // Things that merely look like braces are left alone
+    assert_eq!(template.render("{{ a.b }} {}").unwrap(), "{{ a.b }} {}");
+
+    let hidden = ["HOME".to_string()];
+    let rendered = Template::new(&vars, "sh")
+      .with_hidden_env(&hidden)
+      .render(r#"{{cmd "printf '%s' \"${HOME:-hidden}\""}}"#)
+      .unwrap();
+    assert_eq!(rendered, "hidden");
+  }
+
+  #[test]
+  fn test_render_errors() {
+    let vars = HashMap::new();
+    let template = Template::new(&vars, "sh");
+    assert!(template.render("{{missing}}").is_err());
+    assert!(template.render(r#"{{cmd "exit 3"}}"#).is_err());
+    assert!(template.render(r#"{{nope "x"}}"#).is_err());
+  }
+}
@@ -0,0 +1,155 @@
+use tiktoken_rs::CoreBPE;
+
+use crate::llm_client::{Message, ToolDefinition};
+
+// Counts tokens offline, to size requests before they're sent. OpenAI models are counted exactly
+// with their BPE tables, other models are estimated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tokenizer {
+  O200k,
+  Cl100k,
+  // About 3.5 characters per token, which errs on the high side for most tokenizers
+  Heuristic,
+}
+
+// Tokens every chat message takes beyond its content, and that priming the reply takes
+const MESSAGE_OVERHEAD: u64 = 3;
+pub const REPLY_OVERHEAD: u64 = 3;
+
+impl Tokenizer {
+  pub fn for_model(model: &str) -> Self {
+    // Deployment and fine-tune names often wrap the model name
+    let model = model.rsplit('/').next().unwrap_or(model);
+    let model = model.strip_prefix("ft:").unwrap_or(model);
+    const O200K: &[&str] = &[
+      "gpt-4o", "gpt-4.1", "gpt-4.5", "gpt-5", "gpt-oss", "o1", "o3", "o4",
+    ];
+    const CL100K: &[&str] = &["gpt-4", "gpt-3.5", "gpt-35"];
+    if O200K.iter().any(|prefix| model.starts_with(prefix)) {
+      Tokenizer::O200k
+    } else if CL100K.iter().any(|prefix| model.starts_with(prefix)) {
+      Tokenizer::Cl100k
+    } else {
+      Tokenizer::Heuristic
+    }
+  }
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      Tokenizer::O200k => "o200k_base",
+      Tokenizer::Cl100k => "cl100k_base",
+      Tokenizer::Heuristic => "estimate",
+    }
+  }
+
+  fn bpe(&self) -> Option<&'static CoreBPE> {
+    match self {
+      Tokenizer::O200k => Some(tiktoken_rs::o200k_base_singleton()),
+      Tokenizer::Cl100k => Some(tiktoken_rs::cl100k_base_singleton()),
+      Tokenizer::Heuristic => None,
+    }
+  }
+
+  pub fn count(&self, text: &str) -> u64 {
+    match self.bpe() {
+      Some(bpe) => bpe.encode_ordinary(text).len() as u64,
+      None => (text.len() as u64 * 2).div_ceil(7),
+    }
+  }
+
+  pub fn count_message(&self, message: &Message) -> u64 {
+    let tokens = match message {
+      Message::System { content } | Message::User { content } => self.count(content),
+      Message::Assistant {
+        content,
+        reasoning,
+        tool_calls,
+      } => {
+        let reasoning: u64 = reasoning.iter().map(|r| self.count(&r.text)).sum();
+        let calls: u64 = tool_calls
+          .iter()
+          .flatten()
+          .map(|call| {
+            self.count(&call.id)
+              + self.count(&call.function.name)
+              + self.count(&call.function.arguments.to_string())
+          })
+          .sum();
+        self.count(content.as_deref().unwrap_or_default()) + reasoning + calls
+      }
+      Message::Tool {
+        tool_call_id,
+        content,
+      } => self.count(tool_call_id) + self.count(content),
+    };
+    tokens + MESSAGE_OVERHEAD
+  }
+
+  pub fn count_messages(&self, messages: &[Message]) -> u64 {
+    messages
+      .iter()
+      .map(|message| self.count_message(message))
+      .sum::<u64>()
+      + REPLY_OVERHEAD
+  }
+
+  // Tool definitions are counted as the JSON they're sent as, which is close to what providers
+  // render them into
+  pub fn count_tool(&self, tool: &ToolDefinition) -> u64 {
+    self.count(&serde_json::to_string(&tool.function).unwrap_or_default())
+  }
+
+  pub fn count_tools(&self, tools: &[ToolDefinition]) -> u64 {
+    tools.iter().map(|tool| self.count_tool(tool)).sum()
+  }
+}
+
+// The context window of well-known models, longest prefix first
+pub fn context_window(model: &str) -> Option<u64> {
+  const WINDOWS: &[(&str, u64)] = &[
+    ("gpt-4.1", 1_047_576),
+    ("gpt-4.5", 128_000),
+    ("gpt-4o", 128_000),
+    ("gpt-4-turbo", 128_000),
+    ("gpt-4-32k", 32_768),
+    ("gpt-4", 8_192),
+    ("gpt-3.5-turbo", 16_385),
+    ("gpt-5", 400_000),
+    ("gpt-oss", 131_072),
+    ("o1", 200_000),
+    ("o3", 200_000),
+    ("o4", 200_000),
+    ("claude", 200_000),
+  ];
+  let model = model.rsplit('/').next().unwrap_or(model);
+  WINDOWS
+    .iter()
+    .find(|(prefix, _)| model.starts_with(prefix))
+    .map(|&(_, window)| window)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_tokenizer_counts() {
+    assert_eq!(Tokenizer::for_model("gpt-4o-mini"), Tokenizer::O200k);
+    assert_eq!(Tokenizer::for_model("openai/gpt-4"), Tokenizer::Cl100k);
+    assert_eq!(
+      Tokenizer::for_model("claude-sonnet-4-5"),
+      Tokenizer::Heuristic
+    );
+
+    assert_eq!(Tokenizer::O200k.count("hello world"), 2);
+    assert_eq!(Tokenizer::Cl100k.count("hello world"), 2);
+    assert_eq!(Tokenizer::Heuristic.count("hello world"), 4);
+
+    let messages = [Message::User {
+      content: "hello world".to_string(),
+    }];
+    assert_eq!(Tokenizer::O200k.count_messages(&messages), 8);
+    assert_eq!(context_window("gpt-4-0613"), Some(8_192));
+    assert_eq!(context_window("llama3"), None);
+  }
+}
@@ -0,0 +1,204 @@
+use anyhow::Result;
+use jaq_core::load::{Arena, File, Loader};
+use jaq_core::{Compiler, Ctx, Filter, Native, RcIter};
+use jaq_json::Val;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::sync::LazyLock;
+use tracing::warn;
+
+static ANSI_ESCAPE: LazyLock<Regex> = LazyLock::new(|| {
+  Regex::new(r"\x1b\[[0-?]*[ -/]*[@-~]|\x1b\][^\x07\x1b]*(?:\x07|\x1b\\)|\x1b[@-Z\\-_]").unwrap()
+});
+
+// Share of undecodable characters above which output is taken for binary
+const BINARY_RATIO: f64 = 0.1;
+
+// A step of a tool's `output:` pipeline, applied in order to its stdout and stderr before they're
+// rendered for the model
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Transform {
+  StripAnsi,
+  // Replaces output that isn't text with a note
+  DetectBinary,
+  // Runs a jq expression on output that is JSON, strings come out without quotes
+  Jq(String),
+  // Keeps the matches of a regex, or of its first group, one per line
+  Extract(String),
+  Head(usize),
+  Tail(usize),
+  // Collapses runs of the same line
+  Dedupe,
+  Redact(String),
+}
+
+impl Transform {
+  pub fn validate(&self) -> Result<()> {
+    match self {
+      // Only compiled, running it on a made up input would fail on most real expressions
+      Transform::Jq(expression) => {
+        compile(expression)?;
+      }
+      Transform::Extract(pattern) | Transform::Redact(pattern) => {
+        Regex::new(pattern)?;
+      }
+      _ => {}
+    }
+    Ok(())
+  }
+
+  fn apply(&self, text: String) -> Result<String> {
+    Ok(match self {
+      Transform::StripAnsi => ANSI_ESCAPE.replace_all(&text, "").into_owned(),
+      Transform::DetectBinary => {
+        let undecodable = text.chars().filter(|&c| c == '\u{fffd}').count();
+        if text.contains('\0') || undecodable as f64 > text.chars().count() as f64 * BINARY_RATIO {
+          format!("[Binary output of {} bytes, not shown]", text.len())
+        } else {
+          text
+        }
+      }
+      // Output the expression doesn't fit, like output that isn't JSON, is left as it is
+      Transform::Jq(expression) => match serde_json::from_str(&text) {
+        Ok(json) => run(expression, json).unwrap_or_else(|e| {
+          warn!("{}, leaving the output as it is", e);
+          text
+        }),
+        Err(_) => text,
+      },
+      Transform::Extract(pattern) => {
+        let regex = Regex::new(pattern)?;
+        regex
+          .captures_iter(&text)
+          .map(|captures| captures.get(1).or(captures.get(0)).unwrap().as_str())
+          .collect::<Vec<_>>()
+          .join("\n")
+      }
+      Transform::Head(n) => {
+        let lines: Vec<&str> = text.lines().collect();
+        match lines.len().checked_sub(*n) {
+          Some(more) if more > 0 => {
+            format!("{}\n[... {} more lines]", lines[..*n].join("\n"), more)
+          }
+          _ => text,
+        }
+      }
+      Transform::Tail(n) => {
+        let lines: Vec<&str> = text.lines().collect();
+        match lines.len().checked_sub(*n) {
+          Some(more) if more > 0 => {
+            format!("[{} lines before ...]\n{}", more, lines[more..].join("\n"))
+          }
+          _ => text,
+        }
+      }
+      Transform::Dedupe => {
+        let mut lines: Vec<(&str, usize)> = Vec::new();
+        for line in text.lines() {
+          match lines.last_mut() {
+            Some((last, count)) if *last == line => *count += 1,
+            _ => lines.push((line, 1)),
+          }
+        }
+        lines
+          .into_iter()
+          .map(|(line, count)| match count {
+            1 => line.to_string(),
+            _ => format!("{} [repeated {} times]", line, count),
+          })
+          .collect::<Vec<_>>()
+          .join("\n")
+      }
+      Transform::Redact(pattern) => Regex::new(pattern)?
+        .replace_all(&text, "[REDACTED]")
+        .into_owned(),
+    })
+  }
+}
+
+pub fn apply(transforms: &[Transform], text: &str) -> Result<String> {
+  transforms
+    .iter()
+    .try_fold(text.to_string(), |text, transform| transform.apply(text))
+}
+
+fn compile(expression: &str) -> Result<Filter<Native<Val>>> {
+  let program = File {
+    code: expression,
+    path: (),
+  };
+  let loader = Loader::new(jaq_std::defs().chain(jaq_json::defs()));
+  let arena = Arena::default();
+  let modules = loader
+    .load(&arena, program)
+    .map_err(|errors| anyhow::anyhow!("Invalid jq expression {}: {:?}", expression, errors))?;
+  Compiler::default()
+    .with_funs(jaq_std::funs().chain(jaq_json::funs()))
+    .compile(modules)
+    .map_err(|errors| anyhow::anyhow!("Invalid jq expression {}: {:?}", expression, errors))
+}
+
+fn run(expression: &str, input: serde_json::Value) -> Result<String> {
+  let filter = compile(expression)?;
+  let inputs = RcIter::new(core::iter::empty());
+  let mut results = Vec::new();
+  for value in filter.run((Ctx::new([], &inputs), Val::from(input))) {
+    let value = value.map_err(|e| anyhow::anyhow!("jq expression {} failed: {}", expression, e))?;
+    results.push(match serde_json::Value::from(value) {
+      serde_json::Value::String(text) => text,
+      value => value.to_string(),
+    });
+  }
+  Ok(results.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_output_pipeline() {
+    let tool: crate::config::Tool = serde_yaml::from_str(
+      r#"
+name: build
+description: Build
+input_schema: []
+command: cargo build
+output:
+  - strip_ansi
+  - dedupe
+  - redact: "token=\\S+"
+  - tail: 3
+"#,
+    )
+    .unwrap();
+    let transforms = tool.output;
+    let output = "\x1b[1;32mCompiling\x1b[0m a\nwarning: x\nwarning: x\nwarning: x\nerror: y\nsent \
+                  token=abc123\n";
+    assert_eq!(
+      apply(&transforms, output).unwrap(),
+      "[1 lines before ...]\nwarning: x [repeated 3 times]\nerror: y\nsent [REDACTED]"
+    );
+
+    let jq = [Transform::Jq(".items[] | .name".to_string())];
+    assert_eq!(
+      apply(&jq, r#"{"items": [{"name": "a"}, {"name": "b"}]}"#).unwrap(),
+      "a\nb"
+    );
+    assert_eq!(apply(&jq, "not json").unwrap(), "not json");
+    // JSON the expression fails on is left as it is too
+    assert_eq!(apply(&jq, "[1, 2]").unwrap(), "[1, 2]");
+    assert!(Transform::Jq(".items[".to_string()).validate().is_err());
+
+    let extract = [Transform::Extract(r"error\[(E\d+)\]".to_string())];
+    assert_eq!(
+      apply(&extract, "error[E0308]: mismatched\nerror[E0425]: missing").unwrap(),
+      "E0308\nE0425"
+    );
+    assert_eq!(
+      apply(&[Transform::DetectBinary], "\u{7f}ELF\0\0").unwrap(),
+      "[Binary output of 6 bytes, not shown]"
+    );
+  }
+}
@@ -0,0 +1,221 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ops::AddAssign;
+
+use crate::llm_client::Usage;
+
+// Prices in USD per million tokens
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModelPricing {
+  pub input: f64,
+  // Defaults to the input price
+  pub cached_input: Option<f64>,
+  // Writing to the prompt cache, defaults to the input price
+  pub cache_write: Option<f64>,
+  pub output: f64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct TokenUsage {
+  // Includes cached tokens
+  pub prompt_tokens: u64,
+  pub completion_tokens: u64,
+  pub cached_tokens: u64,
+  // Also included in prompt tokens, only reported by Anthropic
+  pub cache_write_tokens: u64,
+  // Included in completion tokens
+  pub reasoning_tokens: u64,
+}
+
+impl From<&Usage> for TokenUsage {
+  fn from(usage: &Usage) -> Self {
+    Self {
+      prompt_tokens: usage.prompt_tokens,
+      completion_tokens: usage.completion_tokens,
+      cached_tokens: usage
+        .prompt_tokens_details
+        .as_ref()
+        .and_then(|details| details.cached_tokens)
+        .unwrap_or_default(),
+      cache_write_tokens: usage
+        .prompt_tokens_details
+        .as_ref()
+        .and_then(|details| details.cache_write_tokens)
+        .unwrap_or_default(),
+      reasoning_tokens: usage
+        .completion_tokens_details
+        .as_ref()
+        .and_then(|details| details.reasoning_tokens)
+        .unwrap_or_default(),
+    }
+  }
+}
+
+impl AddAssign for TokenUsage {
+  fn add_assign(&mut self, other: Self) {
+    self.prompt_tokens += other.prompt_tokens;
+    self.completion_tokens += other.completion_tokens;
+    self.cached_tokens += other.cached_tokens;
+    self.cache_write_tokens += other.cache_write_tokens;
+    self.reasoning_tokens += other.reasoning_tokens;
+  }
+}
+
+impl ModelPricing {
+  pub fn cost(&self, usage: &TokenUsage) -> f64 {
+    let uncached = usage
+      .prompt_tokens
+      .saturating_sub(usage.cached_tokens + usage.cache_write_tokens);
+    let cached_price = self.cached_input.unwrap_or(self.input);
+    let cache_write_price = self.cache_write.unwrap_or(self.input);
+    (uncached as f64 * self.input
+      + usage.cached_tokens as f64 * cached_price
+      + usage.cache_write_tokens as f64 * cache_write_price
+      + usage.completion_tokens as f64 * self.output)
+      / 1_000_000.0
+  }
+}
+
+// Accumulates usage over a run and prices each turn with the entry of the pricing table whose
+// name is the longest prefix of the model that answered it, so dated model versions share their
+// base price
+pub struct UsageTracker {
+  pricing: HashMap<String, ModelPricing>,
+  pub total: TokenUsage,
+  pub turns: usize,
+  // None once a turn was answered by a model without a price, or if the run's model has none
+  cost: Option<f64>,
+}
+
+impl UsageTracker {
+  pub fn new(model: &str, pricing: &HashMap<String, ModelPricing>) -> Self {
+    let mut tracker = Self {
+      pricing: pricing.clone(),
+      total: TokenUsage::default(),
+      turns: 0,
+      cost: None,
+    };
+    tracker.cost = tracker.pricing(model).map(|_| 0.0);
+    tracker
+  }
+
+  // Returns the cost of the turn, if the model that answered it has a price
+  pub fn record(&mut self, model: &str, usage: TokenUsage) -> Option<f64> {
+    let cost = self.pricing(model).map(|pricing| pricing.cost(&usage));
+    self.total += usage;
+    self.turns += 1;
+    self.cost = self.cost.zip(cost).map(|(total, cost)| total + cost);
+    cost
+  }
+
+  pub fn total_cost(&self) -> Option<f64> {
+    self.cost
+  }
+
+  fn pricing(&self, model: &str) -> Option<&ModelPricing> {
+    self
+      .pricing
+      .iter()
+      .filter(|(name, _)| model.starts_with(name.as_str()))
+      .max_by_key(|(name, _)| name.len())
+      .map(|(_, pricing)| pricing)
+  }
+
+  pub fn summary(&self) -> String {
+    let total = &self.total;
+    let mut summary = format!(
+      "Usage over {} turns: {} prompt tokens ({} cached), {} completion tokens ({} reasoning)",
+      self.turns,
+      total.prompt_tokens,
+      total.cached_tokens,
+      total.completion_tokens,
+      total.reasoning_tokens
+    );
+    if total.cache_write_tokens > 0 {
+      summary.push_str(&format!(
+        ", {} written to the cache",
+        total.cache_write_tokens
+      ));
+    }
+    if let Some(cost) = self.total_cost() {
+      summary.push_str(&format!(", cost ${:.4}", cost));
+    }
+    summary
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_usage_tracker_cost() {
+    let pricing: HashMap<String, ModelPricing> = [
+      (
+        "gpt-4.1".to_string(),
+        ModelPricing {
+          input: 2.0,
+          cached_input: Some(0.5),
+          cache_write: None,
+          output: 8.0,
+        },
+      ),
+      (
+        "gpt-4.1-mini".to_string(),
+        ModelPricing {
+          input: 0.4,
+          cached_input: None,
+          cache_write: Some(0.5),
+          output: 1.6,
+        },
+      ),
+    ]
+    .into_iter()
+    .collect();
+
+    let mut tracker = UsageTracker::new("gpt-4.1-2025-04-14", &pricing);
+    let cost = tracker.record(
+      "gpt-4.1-2025-04-14",
+      TokenUsage {
+        prompt_tokens: 1_000_000,
+        completion_tokens: 100_000,
+        cached_tokens: 500_000,
+        cache_write_tokens: 0,
+        reasoning_tokens: 0,
+      },
+    );
+    assert_eq!(cost, Some(1.0 + 0.25 + 0.8));
+    assert_eq!(tracker.total.prompt_tokens, 1_000_000);
+
+    // A turn a fallback answered is priced as its model
+    let cost = tracker.record(
+      "gpt-4.1-mini",
+      TokenUsage {
+        prompt_tokens: 1_000_000,
+        ..Default::default()
+      },
+    );
+    assert_eq!(cost, Some(0.4));
+    assert_eq!(tracker.total_cost(), Some(1.0 + 0.25 + 0.8 + 0.4));
+
+    // Tokens written to the cache have their own price
+    let cost = tracker.record(
+      "gpt-4.1-mini",
+      TokenUsage {
+        prompt_tokens: 1_000_000,
+        cache_write_tokens: 1_000_000,
+        ..Default::default()
+      },
+    );
+    assert_eq!(cost, Some(0.5));
+    assert!(tracker.summary().contains("1000000 written to the cache"));
+    tracker.record("unknown", TokenUsage::default());
+    assert_eq!(tracker.total_cost(), None);
+
+    assert!(
+      UsageTracker::new("unknown", &pricing)
+        .total_cost()
+        .is_none()
+    );
+  }
+}